@@ -0,0 +1,164 @@
+// the Internet checksum of RFC 1071, shared by team_a and team_b. It is the
+// ones' complement of the ones' complement sum of the data as big-endian 16
+// bit words, used by the IPv4, TCP, UDP and ICMP headers
+//
+// the sum does not depend on the byte order the words are added in as long as
+// the result is swapped back, and a word of 2^16 is the same as 1, so the data
+// is added as native-endian u64 words and folded down at the end
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd;
+
+// a checksum fed in pieces, e.g. a pseudo header, a header and the payload
+// kept in different buffers. Pieces may have any length
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Checksum {
+    sum: u64,
+    // the data so far has an odd length, so the next piece starts in the
+    // middle of a word
+    odd: bool,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        Checksum::default()
+    }
+
+    pub fn add_bytes(mut self, data: &[u8]) -> Checksum {
+        let sum = ones_sum(data);
+        // each byte of a piece that starts in the middle of a word ends up
+        // in the other half of its word
+        self.sum += match self.odd {
+            true => sum.swap_bytes(),
+            false => sum,
+        } as u64;
+        self.odd ^= data.len() % 2 == 1;
+        self
+    }
+
+    pub fn add_u16(self, value: u16) -> Checksum {
+        self.add_bytes(&value.to_be_bytes())
+    }
+
+    pub fn add_u32(self, value: u32) -> Checksum {
+        self.add_bytes(&value.to_be_bytes())
+    }
+
+    // the ones' complement sum, not complemented
+    pub fn sum(self) -> u16 {
+        fold(self.sum)
+    }
+
+    // what goes into the checksum field. Summing data that includes a correct
+    // checksum gives 0
+    pub fn finish(self) -> u16 {
+        !self.sum()
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    Checksum::new().add_bytes(data).finish()
+}
+
+// https://datatracker.ietf.org/doc/html/rfc1624#section-3
+// the checksum after the 16 bit word `old` at an even offset was replaced by
+// `new`, e.g. for a TTL decrement, without summing the whole header again
+pub fn update(checksum: u16, old: u16, new: u16) -> u16 {
+    !fold(!checksum as u64 + !old as u64 + new as u64)
+}
+
+// like update for a 32 bit field, e.g. an address rewritten by NAT
+pub fn update_u32(checksum: u16, old: u32, new: u32) -> u16 {
+    let checksum = update(checksum, (old >> 16) as u16, (new >> 16) as u16);
+    update(checksum, old as u16, new as u16)
+}
+
+// the big-endian ones' complement sum of data, padded with a zero byte if its
+// length is odd
+fn ones_sum(data: &[u8]) -> u16 {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if let Some(sum) = simd::ones_sum(data) {
+        return sum;
+    }
+
+    scalar_sum(data)
+}
+
+fn scalar_sum(data: &[u8]) -> u16 {
+    let (sum, rest) = add_words(0, data);
+    from_native(fold(add_tail(sum, rest)))
+}
+
+// adds the data as native-endian u64 words with end-around carry, returns
+// the bytes that do not fill a whole word
+fn add_words(mut sum: u64, data: &[u8]) -> (u64, &[u8]) {
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        sum = add_carry(sum, u64::from_ne_bytes(word.try_into().unwrap()));
+    }
+
+    (sum, words.remainder())
+}
+
+// the last few bytes, as native-endian u16 words
+fn add_tail(mut sum: u64, data: &[u8]) -> u64 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum = add_carry(sum, u16::from_ne_bytes([word[0], word[1]]) as u64);
+    }
+    if let [last] = words.remainder() {
+        sum = add_carry(sum, u16::from_ne_bytes([*last, 0]) as u64);
+    }
+
+    sum
+}
+
+fn add_carry(sum: u64, value: u64) -> u64 {
+    let (sum, carry) = sum.overflowing_add(value);
+    sum + carry as u64
+}
+
+fn fold(mut sum: u64) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+// a sum of native-endian words as a big-endian one
+fn from_native(sum: u16) -> u16 {
+    u16::from_be(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the example of https://datatracker.ietf.org/doc/html/rfc1071#section-3
+    #[test]
+    fn rfc1071_example() {
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(Checksum::new().add_bytes(&data).sum(), 0xddf2);
+        assert_eq!(checksum(&data), !0xddf2);
+    }
+
+    // a real IPv4 header, the checksum field is 0xb861
+    #[test]
+    fn ipv4_header() {
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header), 0);
+
+        header[10..12].fill(0);
+        assert_eq!(checksum(&header), 0xb861);
+
+        // TTL 64 -> 63, the TTL shares its word with the protocol
+        let updated = update(0xb861, 0x4011, 0x3f11);
+        header[8] = 0x3f;
+        header[10..12].copy_from_slice(&updated.to_be_bytes());
+        assert_eq!(checksum(&header), 0);
+    }
+}
@@ -0,0 +1,63 @@
+// AVX2 adds 32 bytes at a time, as eight native-endian u32 words that are
+// widened to u64 lanes so they never overflow
+
+use std::arch::x86_64::*;
+
+use crate::{add_carry, add_tail, add_words, fold, from_native};
+
+// shorter buffers are not worth the setup
+const MIN_LEN: usize = 128;
+
+// None if the buffer is short or the cpu has no AVX2
+pub(crate) fn ones_sum(data: &[u8]) -> Option<u16> {
+    if data.len() < MIN_LEN || !is_x86_feature_detected!("avx2") {
+        return None;
+    }
+
+    // SAFETY: AVX2 is available
+    Some(unsafe { avx2_sum(data) })
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn avx2_sum(data: &[u8]) -> u16 {
+    let zero = _mm256_setzero_si256();
+    let mut lanes = zero;
+
+    let mut blocks = data.chunks_exact(32);
+    for block in &mut blocks {
+        // SAFETY: the block is 32 bytes long, loadu has no alignment requirement
+        let words = unsafe { _mm256_loadu_si256(block.as_ptr() as *const __m256i) };
+        lanes = _mm256_add_epi64(lanes, _mm256_unpacklo_epi32(words, zero));
+        lanes = _mm256_add_epi64(lanes, _mm256_unpackhi_epi32(words, zero));
+    }
+
+    let mut sum = 0;
+    let mut array = [0u64; 4];
+    // SAFETY: array is 32 bytes long
+    unsafe { _mm256_storeu_si256(array.as_mut_ptr() as *mut __m256i, lanes) };
+    for lane in array {
+        sum = add_carry(sum, lane);
+    }
+
+    let (sum, rest) = add_words(sum, blocks.remainder());
+    from_native(fold(add_tail(sum, rest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scalar_sum;
+
+    #[test]
+    fn matches_the_scalar_sum() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+
+        let data: Vec<u8> = (0..4099u32).map(|i| ((i * 7919) >> 3) as u8).collect();
+        for len in [MIN_LEN, 129, 255, 1500, 4099] {
+            assert_eq!(ones_sum(&data[..len]), Some(scalar_sum(&data[..len])));
+        }
+        assert_eq!(ones_sum(&[0xff; 4096]), Some(0xffff));
+    }
+}
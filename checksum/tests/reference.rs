@@ -0,0 +1,72 @@
+// the checksum against a straightforward 16 bits at a time implementation
+
+use checksum::{checksum, update, update_u32, Checksum};
+use proptest::prelude::*;
+
+fn reference(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for word in data.chunks(2) {
+        let word = u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]);
+        sum += word as u32;
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+// 0 and 0xFFFF are both zero in ones' complement, RFC 1624 may give either
+fn same(a: u16, b: u16) -> bool {
+    a == b || (a == 0 && b == 0xFFFF) || (a == 0xFFFF && b == 0)
+}
+
+proptest! {
+    #[test]
+    fn matches_the_reference(data in proptest::collection::vec(any::<u8>(), 0..3000)) {
+        prop_assert_eq!(checksum(&data), reference(&data));
+    }
+
+    #[test]
+    fn pieces_add_up(
+        data in proptest::collection::vec(any::<u8>(), 0..600),
+        cuts in proptest::collection::vec(any::<prop::sample::Index>(), 0..5),
+    ) {
+        let mut cuts: Vec<_> = cuts.iter().map(|cut| cut.index(data.len() + 1)).collect();
+        cuts.sort();
+
+        let mut sum = Checksum::new();
+        let mut start = 0;
+        for cut in cuts.into_iter().chain([data.len()]) {
+            sum = sum.add_bytes(&data[start..cut]);
+            start = cut;
+        }
+        prop_assert_eq!(sum.finish(), reference(&data));
+    }
+
+    #[test]
+    fn a_correct_checksum_sums_to_zero(mut data in proptest::collection::vec(any::<u8>(), 2..200)) {
+        data.truncate(data.len() & !1);
+        data[0..2].fill(0);
+        let sum = checksum(&data);
+        data[0..2].copy_from_slice(&sum.to_be_bytes());
+        prop_assert!(same(checksum(&data), 0));
+    }
+
+    #[test]
+    fn incremental_updates(
+        mut data in proptest::collection::vec(any::<u8>(), 4..200),
+        at in any::<prop::sample::Index>(),
+        new in any::<u32>(),
+    ) {
+        let at = at.index(data.len() / 4) * 4;
+        let before = checksum(&data);
+
+        let old = u16::from_be_bytes([data[at], data[at + 1]]);
+        data[at..at + 2].copy_from_slice(&(new as u16).to_be_bytes());
+        let updated = update(before, old, new as u16);
+        prop_assert!(same(updated, reference(&data)));
+
+        let old = u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+        data[at..at + 4].copy_from_slice(&new.to_be_bytes());
+        prop_assert!(same(update_u32(updated, old, new), reference(&data)));
+    }
+}
@@ -1,5 +1,7 @@
 use std::net::Ipv4Addr;
 
+use checksum::Checksum;
+
 #[derive(Debug)]
 pub struct IPv4Header {
     version: u8,
@@ -16,21 +18,12 @@ pub struct IPv4Header {
     destination_address: Ipv4Addr,
 }
 
+// skips the checksum field
 pub fn get_checksum(buffer: &[u8]) -> u16 {
-    let mut sum: u16 = 0;
-
-    for (index, chunk) in buffer.chunks(2).enumerate() {
-        if index == 5 {
-            continue;
-        }
-        let num = ((chunk[0] as u16) << 8) | chunk[1] as u16;
-        let (new_sum, is_overflowed) = sum.overflowing_add(num);
-        sum = new_sum;
-        if is_overflowed {
-            sum += 1;
-        }
-    }
-    !sum
+    Checksum::new()
+        .add_bytes(&buffer[..10])
+        .add_bytes(&buffer[12..])
+        .finish()
 }
 
 pub fn parse(bytes: &[u8]) -> IPv4Header {
@@ -1,3 +1,5 @@
+use std::fs;
+
 use etherparse::{Icmpv4Header, Icmpv4Type, Ipv4Header, PacketBuilder};
 use tun_tap::{Iface, Mode};
 
@@ -6,11 +8,13 @@ mod tcp_header;
 
 const ICMP_PROTOCOL_NUMBER: u8 = 0x1;
 const IPV4_PROTOCOL_NUMBER: u16 = 0x800;
+const DEFAULT_MTU: usize = 1500;
 
 fn main() {
     let iface = Iface::new("tun1", Mode::Tun).expect("Failed to create a TUN device");
 
-    let mut buffer = vec![0; 1504]; // MTU + 4 for the header
+    let mtu = interface_mtu(iface.name()).unwrap_or(DEFAULT_MTU);
+    let mut buffer = vec![0; mtu + 4]; // MTU + 4 for the header
 
     loop {
         let n = iface.recv(&mut buffer).unwrap();
@@ -47,3 +51,9 @@ fn main() {
         }
     }
 }
+
+// the MTU configured on the interface, e.g. with `ip link set tun1 mtu 9000`
+fn interface_mtu(name: &str) -> Option<usize> {
+    let mtu = fs::read_to_string(format!("/sys/class/net/{name}/mtu")).ok()?;
+    mtu.trim().parse().ok()
+}
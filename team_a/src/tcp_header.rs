@@ -1,3 +1,5 @@
+use checksum::Checksum;
+
 #[derive(Debug)]
 pub struct TCPHeader {
     source_port: u16,
@@ -19,22 +21,14 @@ pub struct TCPHeader {
     urgent_pointer: u16,
 }
 
+// skips the checksum field
 pub fn get_checksum(buffer: &[u8]) -> u16 {
-    let mut sum: u16 = 0;
-
-    for (index, chunk) in buffer.chunks(2).enumerate() {
-        if index == 8 {
-            continue;
-        }
-        let num = ((chunk[0] as u16) << 8) | chunk[1] as u16;
-        let (new_sum, is_overflowed) = sum.overflowing_add(num);
-        sum = new_sum;
-        if is_overflowed {
-            sum += 1;
-        }
-    }
-    println!("{}", !sum);
-    !sum
+    let checksum = Checksum::new()
+        .add_bytes(&buffer[..16])
+        .add_bytes(&buffer[18..])
+        .finish();
+    println!("{}", checksum);
+    checksum
 }
 
 pub fn parse(bytes: &[u8]) -> TCPHeader {
@@ -0,0 +1,122 @@
+// heap allocations per frame while one connection transfers data between two
+// stacks over a MemoryDevice pair. Every allocation in the process is
+// counted, including the ones of the reading and writing threads
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddrV4},
+    os::fd::{AsRawFd, RawFd},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use tcp::{
+    device::{MemoryDevice, NetDevice},
+    net::{TcpListener, TcpStream},
+    ConnectionManager,
+};
+
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+const BYTES: usize = 1024 * 1024;
+
+// what this bench measured on 228467f, the commit before the packet pool,
+// where every frame was built from three Vecs and checksummed over a copy
+const BEFORE_POOL: f64 = 13.16;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+// SAFETY: only counts and forwards to the system allocator
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// counts the frames both stacks send
+struct CountingDevice {
+    inner: MemoryDevice,
+    sent: Arc<AtomicUsize>,
+}
+
+impl AsRawFd for CountingDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl NetDevice for CountingDevice {
+    fn send(&self, frame: &[u8]) -> io::Result<usize> {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.inner.send(frame)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.recv(buf)
+    }
+}
+
+fn main() {
+    let sent = Arc::new(AtomicUsize::new(0));
+    let (server_dev, client_dev) = MemoryDevice::pair().unwrap();
+    let device = |inner| CountingDevice {
+        inner,
+        sent: sent.clone(),
+    };
+    let server = ConnectionManager::new(device(server_dev)).unwrap();
+    let client = ConnectionManager::new(device(client_dev)).unwrap();
+
+    let addr = SocketAddrV4::new(SERVER_IP, 8080);
+    let listener = TcpListener::bind_with(&server, addr).unwrap();
+    let timeout = Some(Duration::from_secs(10));
+    let mut stream = TcpStream::connect_with(&client, CLIENT_IP, addr, timeout).unwrap();
+    let (mut accepted, _) = listener.accept().unwrap();
+
+    let frames_before = sent.load(Ordering::Relaxed);
+    let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+
+    let writer = thread::spawn(move || {
+        let chunk = [0x55; 16 * 1024];
+        for _ in 0..BYTES / chunk.len() {
+            stream.write_all(&chunk).unwrap();
+        }
+        stream
+    });
+    let mut buf = [0; 16 * 1024];
+    let mut read = 0;
+    while read < BYTES {
+        read += accepted.read(&mut buf).unwrap();
+    }
+    let stream = writer.join().unwrap();
+
+    let frames = sent.load(Ordering::Relaxed) - frames_before;
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+    let per_frame = allocations as f64 / frames as f64;
+    eprintln!(
+        "{} KiB in {frames} frames, {allocations} allocations, {per_frame:.2} per frame",
+        BYTES / 1024,
+    );
+    eprintln!("before the pool: {BEFORE_POOL:.2} per frame, now {per_frame:.2}");
+    drop((stream, accepted));
+}
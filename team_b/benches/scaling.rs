@@ -0,0 +1,79 @@
+// throughput of many connections between two stacks with 1, 2 and 4 queues,
+// each queue a MemoryDevice pair with its own processing thread on both
+// sides, so more queues only help with as many cores to run the threads on
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use tcp::{
+    clock::SystemClock,
+    device::MemoryDevice,
+    net::{TcpListener, TcpStream},
+    ConnectionManager,
+};
+
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+const CONNECTIONS: usize = 8;
+const BYTES: usize = 4 * 1024 * 1024;
+
+// how long CONNECTIONS connections take to each send BYTES over `queues` queues
+fn run(queues: usize) -> Duration {
+    let (server_devs, client_devs): (Vec<_>, Vec<_>) =
+        (0..queues).map(|_| MemoryDevice::pair().unwrap()).unzip();
+    let server = ConnectionManager::with_queues(server_devs, Arc::new(SystemClock)).unwrap();
+    let client = ConnectionManager::with_queues(client_devs, Arc::new(SystemClock)).unwrap();
+
+    let addr = SocketAddrV4::new(SERVER_IP, 8080);
+    let listener = TcpListener::bind_with(&server, addr).unwrap();
+    let timeout = Some(Duration::from_secs(10));
+    let pairs: Vec<_> = (0..CONNECTIONS)
+        .map(|_| {
+            let stream = TcpStream::connect_with(&client, CLIENT_IP, addr, timeout).unwrap();
+            (stream, listener.accept().unwrap().0)
+        })
+        .collect();
+
+    let start = Instant::now();
+    let threads: Vec<_> = pairs
+        .into_iter()
+        .flat_map(|(mut stream, mut accepted)| {
+            let writer = thread::spawn(move || {
+                let chunk = [0x55; 16 * 1024];
+                for _ in 0..BYTES / chunk.len() {
+                    stream.write_all(&chunk).unwrap();
+                }
+            });
+            let reader = thread::spawn(move || {
+                let mut buf = [0; 16 * 1024];
+                let mut read = 0;
+                while read < BYTES {
+                    read += accepted.read(&mut buf).unwrap();
+                }
+            });
+            [writer, reader]
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    for queues in [1, 2, 4] {
+        let elapsed = run(queues);
+        let mbytes = (CONNECTIONS * BYTES) as f64 / (1024.0 * 1024.0);
+        eprintln!(
+            "{queues} queue(s): {mbytes:.0} MiB in {elapsed:.2?}, {:.1} MiB/s",
+            mbytes / elapsed.as_secs_f64()
+        );
+    }
+}
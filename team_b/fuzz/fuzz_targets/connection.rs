@@ -0,0 +1,98 @@
+#![no_main]
+
+use std::time::Duration;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use tcp::fuzzing::Stack;
+
+// the remote side of up to four connections, picked by `port % 4`
+#[derive(Arbitrary, Debug)]
+enum Op {
+    // raw bytes straight from the device
+    Frame(Vec<u8>),
+    // a well formed segment, seq and ack are relative to what the stack
+    // expects so that small deltas get through
+    Segment {
+        port: u8,
+        flags: u8,
+        seq_delta: i16,
+        ack_delta: i16,
+        payload: Vec<u8>,
+    },
+    Tick,
+    // moves the virtual clock forward
+    Advance {
+        secs: u8,
+    },
+    Accept,
+    Connect {
+        port: u8,
+    },
+    Write {
+        port: u8,
+        data: Vec<u8>,
+    },
+    Read {
+        port: u8,
+        len: u16,
+    },
+    Close {
+        port: u8,
+    },
+    Abort {
+        port: u8,
+    },
+}
+
+fn remote_port(port: u8) -> u16 {
+    40000 + (port % 4) as u16
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut stack = Stack::new();
+
+    for op in ops {
+        // errors are fine, panics and broken invariants are not
+        let _ = match op {
+            Op::Frame(frame) => stack.input(&frame),
+            Op::Segment {
+                port,
+                flags,
+                seq_delta,
+                ack_delta,
+                payload,
+            } => {
+                let payload = &payload[..payload.len().min(1460)];
+                let frame = stack.segment(
+                    remote_port(port),
+                    flags,
+                    seq_delta as u32,
+                    ack_delta as u32,
+                    payload,
+                );
+                stack.input(&frame)
+            }
+            Op::Tick => {
+                stack.tick();
+                Ok(())
+            }
+            Op::Advance { secs } => {
+                stack.advance(Duration::from_secs(secs.into()));
+                Ok(())
+            }
+            Op::Accept => stack.accept(),
+            Op::Connect { port } => stack.connect(remote_port(port)),
+            Op::Write { port, data } => stack.write(remote_port(port), &data).map(drop),
+            Op::Read { port, len } => {
+                let mut buf = vec![0; len as usize];
+                stack.read(remote_port(port), &mut buf).map(drop)
+            }
+            Op::Close { port } => stack.close(remote_port(port)),
+            Op::Abort { port } => stack.abort(remote_port(port)),
+        };
+
+        stack.check_invariants();
+        stack.output();
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tcp::ipv4::IPv4Header;
+
+// parse -> serialize -> parse gives back the same header and payload
+fuzz_target!(|data: &[u8]| {
+    let Ok((ip, payload)) = IPv4Header::new(data) else {
+        return;
+    };
+
+    let mut packet = ip.serialize()[..ip.size()].to_vec();
+    packet.extend(payload);
+
+    let (reparsed, repayload) = IPv4Header::new(&packet).expect("serialized header must parse");
+    assert_eq!(reparsed, ip);
+    assert_eq!(repayload, payload);
+    assert_eq!(reparsed.calc_checksum(), ip.calc_checksum());
+});
@@ -0,0 +1,38 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tcp::ipv4::{IPv4Header, Ipv4Option};
+
+// every option that parses serializes back to something that parses the same
+fuzz_target!(|options: &[u8]| {
+    let options = &options[..options.len().min(40) / 4 * 4];
+    let ip = IPv4Header {
+        version: 4,
+        ihl: 5 + options.len() as u8 / 4,
+        dscp: 0,
+        ecn: 0,
+        total_length: 20 + options.len() as u16,
+        identification: 0,
+        flags: 0,
+        fragment_offset: 0,
+        time_to_live: 64,
+        protocol: 6,
+        header_checksum: 0,
+        source_ip: 0,
+        dest_ip: 0,
+        options,
+    };
+
+    let parsed: Vec<Ipv4Option> = ip.parse_options().map_while(Result::ok).collect();
+
+    let mut reserialized: Vec<u8> = parsed.iter().flat_map(Ipv4Option::serialize).collect();
+    assert!(reserialized.len() <= options.len());
+    reserialized.resize(options.len(), 0);
+
+    let ip = IPv4Header {
+        options: &reserialized,
+        ..ip
+    };
+    let reparsed: Vec<Ipv4Option> = ip.parse_options().map_while(Result::ok).collect();
+    assert_eq!(reparsed, parsed);
+});
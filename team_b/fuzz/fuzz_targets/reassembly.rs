@@ -0,0 +1,87 @@
+#![no_main]
+
+use std::time::{Duration, Instant};
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use tcp::{
+    fragment::{fragment, Reassembler, MAX_FRAGMENTS, MEMORY},
+    ipv4::IPv4Header,
+};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    // fragmented with `mtu` and inserted in `order`, indexes are taken
+    // modulo the number of fragments and repeats are duplicates
+    payload: Vec<u8>,
+    mtu: u16,
+    order: Vec<u8>,
+    // raw packets for a second reassembler, with the clock moving on by
+    // the given milliseconds before each one
+    raw: Vec<(Vec<u8>, u16)>,
+}
+
+// any order of the fragments gives back the datagram, and no input makes
+// the reassembler hold more than its limit
+fuzz_target!(|input: Input| {
+    let payload = &input.payload[..input.payload.len().min(u16::MAX as usize - 20)];
+    let ip = IPv4Header {
+        version: 4,
+        ihl: 5,
+        dscp: 0,
+        ecn: 0,
+        total_length: 20 + payload.len() as u16,
+        identification: 1,
+        flags: 0,
+        fragment_offset: 0,
+        time_to_live: 64,
+        protocol: 6,
+        header_checksum: 0,
+        source_ip: 0x0a000002,
+        dest_ip: 0x0a000003,
+        options: &[],
+    };
+    let mut datagram = ip.serialize()[..20].to_vec();
+    datagram[10..12].copy_from_slice(&ip.calc_checksum().to_be_bytes());
+    datagram.extend(payload);
+
+    let mtu = 68 + input.mtu as usize;
+    let mut fragments = Vec::new();
+    fragment(&datagram, mtu, 1, |header, payload| {
+        fragments.push([header, payload].concat())
+    })
+    .unwrap();
+    if fragments.len() > MAX_FRAGMENTS {
+        return;
+    }
+
+    let now = Instant::now();
+    let mut reassembler = Reassembler::new();
+    let mut inserted = vec![false; fragments.len()];
+    let order = input.order.iter().map(|&i| i as usize % fragments.len());
+    let rest = 0..fragments.len();
+    for i in order.chain(rest) {
+        if inserted.iter().all(|&done| done) {
+            break;
+        }
+        let result = reassembler.insert(&fragments[i], now).unwrap();
+        inserted[i] = true;
+        match inserted.iter().all(|&done| done) {
+            true => assert_eq!(result.as_deref(), Some(&datagram[..])),
+            false => assert_eq!(result, None),
+        }
+    }
+    assert_eq!(reassembler.memory(), 0);
+
+    let mut reassembler = Reassembler::new();
+    let mut now = now;
+    for (packet, ms) in &input.raw {
+        now += Duration::from_millis(*ms as u64);
+        if let Ok(Some(datagram)) = reassembler.insert(packet, now) {
+            let (ip, _) = IPv4Header::new(&datagram).expect("reassembled datagram must parse");
+            assert!(!ip.is_fragment());
+            assert_eq!(ip.header_checksum, ip.calc_checksum());
+        }
+        assert!(reassembler.memory() <= MEMORY);
+    }
+});
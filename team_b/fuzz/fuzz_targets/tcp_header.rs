@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tcp::tcp::TcpHeader;
+
+// parse -> serialize -> parse gives back the same header and payload
+fuzz_target!(|data: &[u8]| {
+    let Ok((tcp, text)) = TcpHeader::new(data) else {
+        return;
+    };
+
+    let mut segment = tcp.serialize()[..tcp.size()].to_vec();
+    segment.extend(text);
+
+    let (reparsed, retext) = TcpHeader::new(&segment).expect("serialized header must parse");
+    assert_eq!(reparsed, tcp);
+    assert_eq!(retext, text);
+
+    // the pseudo header length field is 16 bits, like in an IPv4 packet
+    if segment.len() <= u16::MAX as usize {
+        let len = segment.len();
+        assert_eq!(
+            reparsed.calc_checksum(1, 2, len, retext),
+            tcp.calc_checksum(1, 2, len, text)
+        );
+    }
+});
@@ -0,0 +1,34 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tcp::tcp::{TcpHeader, TcpOption};
+
+// every option that parses serializes back to something that parses the same
+fuzz_target!(|options: &[u8]| {
+    let options = &options[..options.len().min(40) / 4 * 4];
+    let tcp = TcpHeader {
+        source_port: 0,
+        dest_port: 0,
+        sequence_number: 0,
+        ack_number: 0,
+        data_offset: 5 + options.len() as u8 / 4,
+        flags: 0,
+        window_size: 0,
+        checksum: 0,
+        urgent_pointer: 0,
+        options,
+    };
+
+    let parsed: Vec<TcpOption> = tcp.parse_options().map_while(Result::ok).collect();
+
+    let mut reserialized: Vec<u8> = parsed.iter().flat_map(TcpOption::serialize).collect();
+    assert!(reserialized.len() <= options.len());
+    reserialized.resize(options.len(), 0);
+
+    let tcp = TcpHeader {
+        options: &reserialized,
+        ..tcp
+    };
+    let reparsed: Vec<TcpOption> = tcp.parse_options().map_while(Result::ok).collect();
+    assert_eq!(reparsed, parsed);
+});
@@ -0,0 +1,140 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    error::{Error, Result},
+    listener::Listener,
+    Connection, ConnectionHandle, State,
+};
+
+// runtime-agnostic: a pending operation leaves its waker with the connection
+// and the processing thread wakes it after the next packet for it
+
+// one task waits per direction, like with a single AsyncRead/AsyncWrite user.
+// Polling again keeps the stored waker unless it would wake another task
+fn register(slot: &mut Option<Waker>, waker: &Waker) {
+    if !slot.as_ref().is_some_and(|stored| stored.will_wake(waker)) {
+        *slot = Some(waker.clone());
+    }
+}
+
+impl ConnectionHandle {
+    fn poll_op<T>(
+        &self,
+        cx: &mut Context,
+        slot: fn(&mut Connection) -> &mut Option<Waker>,
+        op: impl FnOnce(&mut Connection) -> Result<T>,
+    ) -> Poll<io::Result<T>> {
+        let mut conn = match self.lock() {
+            Ok(conn) => conn,
+            Err(e) => return Poll::Ready(Err(e.into())),
+        };
+
+        match op(&mut conn) {
+            Err(Error::WouldBlock) => {
+                register(slot(&mut conn), cx.waker());
+                Poll::Pending
+            }
+            result => Poll::Ready(result.map_err(Into::into)),
+        }
+    }
+}
+
+impl AsyncRead for ConnectionHandle {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = self.poll_op(
+            cx,
+            |conn| &mut conn.read_waker,
+            |conn| conn.read(buf).map(|read| (read, conn.window_update)),
+        );
+        if let Poll::Ready(Ok((_, true))) = result {
+            self.changed();
+        }
+        result.map_ok(|(read, _)| read)
+    }
+}
+
+impl AsyncWrite for ConnectionHandle {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = self.poll_op(cx, |conn| &mut conn.write_waker, |conn| conn.write(buf));
+        if matches!(result, Poll::Ready(Ok(written)) if written > 0) {
+            self.changed();
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // sends FIN and resolves once the peer has acknowledged everything
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut started = false;
+        let result = self.poll_op(
+            cx,
+            |conn| &mut conn.write_waker,
+            |conn| {
+                started = !conn.closing;
+                conn.closing = true;
+
+                if conn.delivered() {
+                    Ok(())
+                } else if conn.reset {
+                    Err(conn.reset_error())
+                } else if conn.state == State::Closed {
+                    Err(Error::NotConnected)
+                } else {
+                    Err(Error::WouldBlock)
+                }
+            },
+        );
+        // the processing thread only has to be told once to send the FIN
+        if started {
+            self.changed();
+        }
+        result
+    }
+}
+
+pub struct Accept<'a> {
+    listener: &'a Listener,
+}
+
+impl Future for Accept<'_> {
+    type Output = Result<ConnectionHandle>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let listener = self.listener;
+        let addr = (listener.ip, listener.port);
+        if let Err(e) = listener.mgr.check_running() {
+            return Poll::Ready(Err(e));
+        }
+
+        // the Manager stays locked until the waker is registered, so a SYN
+        // can't slip in between
+        let mut mgr = listener.mgr.listeners().mgr.lock().unwrap();
+        match listener.mgr.try_accept(&mut mgr, addr.0, addr.1) {
+            Err(Error::WouldBlock) => {
+                register(&mut listener.conn.lock().unwrap().read_waker, cx.waker());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+impl Listener {
+    pub fn accept_async(&self) -> Accept<'_> {
+        Accept { listener: self }
+    }
+}
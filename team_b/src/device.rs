@@ -0,0 +1,335 @@
+// the link the stack sends and receives frames on. Frames start with the
+// 4 byte packet information header of a tun device, followed by a virtio-net
+// header if the device has offloads, followed by the IP packet
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+};
+
+use log::{info, warn};
+use nix::libc;
+use tun_tap::{Iface, Mode};
+
+// the processing thread polls the fd for POLLIN before calling recv
+pub trait NetDevice: AsRawFd + Send + 'static {
+    fn send(&self, frame: &[u8]) -> io::Result<usize>;
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    // plain frames unless the device says otherwise
+    fn offloads(&self) -> Offloads {
+        Offloads::default()
+    }
+
+    // the largest IP packet the link carries, segments are sized to fit it
+    fn mtu(&self) -> usize {
+        DEFAULT_MTU
+    }
+}
+
+// of ethernet, and of a tun device unless it is configured otherwise
+pub const DEFAULT_MTU: usize = 1500;
+
+// the MTU configured on interface `name`, e.g. with `ip link set tun0 mtu 9000`
+pub fn interface_mtu(name: &str) -> io::Result<usize> {
+    let mtu = fs::read_to_string(format!("/sys/class/net/{name}/mtu"))?;
+    mtu.trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid MTU"))
+}
+
+// work the device does in place of the stack
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Offloads {
+    // every frame carries a VnetHeader, in both directions
+    pub vnet_hdr: bool,
+    // outgoing TCP checksums may be left partial for the device to complete,
+    // incoming ones may be partial or already verified
+    pub checksum: bool,
+    // outgoing TCP segments of up to 64 KiB are cut into MSS sized ones, and
+    // incoming ones may have been merged into segments that large. Needs
+    // `checksum` as well
+    pub tso: bool,
+}
+
+impl Offloads {
+    // what comes before the IP packet
+    pub fn header_len(&self) -> usize {
+        match self.vnet_hdr {
+            true => 4 + VNET_HDR_LEN,
+            false => 4,
+        }
+    }
+
+    // the largest frame the device hands to the stack on a link with `mtu`
+    pub fn max_frame(&self, mtu: usize) -> usize {
+        match self.tso {
+            true => self.header_len() + mtu.max(u16::MAX as usize),
+            false => self.header_len() + mtu,
+        }
+    }
+}
+
+pub const VNET_HDR_LEN: usize = 10;
+
+// struct virtio_net_hdr from linux/virtio_net.h, in native byte order like
+// tun uses it by default
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VnetHeader {
+    pub flags: u8,
+    pub gso_type: u8,
+    // bytes of headers in front of the payload
+    pub hdr_len: u16,
+    // the payload size of the segments a GSO frame is cut into
+    pub gso_size: u16,
+    // the checksum over the bytes from csum_start on goes to csum_start + csum_offset
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VnetHeader {
+    pub const NEEDS_CSUM: u8 = 1;
+    pub const DATA_VALID: u8 = 2;
+    pub const GSO_NONE: u8 = 0;
+    pub const GSO_TCPV4: u8 = 1;
+
+    pub fn new(data: &[u8]) -> Option<VnetHeader> {
+        let data = data.get(..VNET_HDR_LEN)?;
+        let u16_at = |i: usize| u16::from_ne_bytes([data[i], data[i + 1]]);
+
+        Some(VnetHeader {
+            flags: data[0],
+            gso_type: data[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        })
+    }
+
+    // writes the VNET_HDR_LEN bytes of the header to the start of `data`
+    pub fn serialize_into(&self, data: &mut [u8]) {
+        data[0] = self.flags;
+        data[1] = self.gso_type;
+        data[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        data[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        data[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        data[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+    }
+}
+
+impl NetDevice for Iface {
+    fn send(&self, frame: &[u8]) -> io::Result<usize> {
+        Iface::send(self, frame)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Iface::recv(self, buf)
+    }
+
+    fn mtu(&self) -> usize {
+        interface_mtu(self.name()).unwrap_or(DEFAULT_MTU)
+    }
+}
+
+// needs CAP_NET_ADMIN, see run.sh
+pub fn tun(name: &str) -> io::Result<Iface> {
+    Iface::new(name, Mode::Tun)
+}
+
+// TUNSETIFF is declared as taking an int but really takes a struct ifreq
+nix::ioctl_write_ptr_bad!(
+    tun_set_iff,
+    nix::request_code_write!(b'T', 202, std::mem::size_of::<libc::c_int>()),
+    libc::ifreq
+);
+
+// TUNSETOFFLOAD, takes the TUN_F_* flags by value
+nix::ioctl_write_int_bad!(
+    tun_set_offload,
+    nix::request_code_write!(b'T', 208, std::mem::size_of::<libc::c_uint>())
+);
+
+// from linux/if_tun.h
+const TUN_F_CSUM: libc::c_int = 0x01;
+const TUN_F_TSO4: libc::c_int = 0x02;
+
+// one queue of a tun device opened with IFF_MULTI_QUEUE. The kernel spreads
+// the flows over the queues, frames can be sent on any of them
+#[derive(Debug)]
+pub struct TunQueue {
+    file: File,
+    offloads: Offloads,
+    mtu: usize,
+}
+
+// `queues` queues of the same device, one per processing thread, see
+// ConnectionManager::with_queues. Needs CAP_NET_ADMIN, and a persistent
+// device has to have been created with multi_queue
+// checksum offload and TSO are negotiated on the first queue, the others
+// follow it. If the kernel refuses them the queues use plain frames. The MTU
+// is read from the interface once it exists
+pub fn tun_queues(name: &str, queues: usize) -> io::Result<Vec<TunQueue>> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "name too long"));
+    }
+    if queues == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut flags = libc::IFF_TUN | libc::IFF_MULTI_QUEUE;
+    let (first, offloads) = match open_with_offloads(name, flags) {
+        Ok(first) => {
+            flags |= libc::IFF_VNET_HDR;
+            let offloads = Offloads {
+                vnet_hdr: true,
+                checksum: true,
+                tso: true,
+            };
+            (first, offloads)
+        }
+        Err(e) => {
+            info!("no offloads on {name}: {e}");
+            (open_queue(name, flags)?, Offloads::default())
+        }
+    };
+
+    let mtu = interface_mtu(name).unwrap_or_else(|e| {
+        warn!("no MTU for {name}, using {DEFAULT_MTU}: {e}");
+        DEFAULT_MTU
+    });
+
+    let mut output = vec![TunQueue {
+        file: first,
+        offloads,
+        mtu,
+    }];
+    for _ in 1..queues {
+        let file = open_queue(name, flags)?;
+        output.push(TunQueue {
+            file,
+            offloads,
+            mtu,
+        });
+    }
+
+    Ok(output)
+}
+
+fn open_with_offloads(name: &str, flags: libc::c_int) -> io::Result<File> {
+    let file = open_queue(name, flags | libc::IFF_VNET_HDR)?;
+    // SAFETY: the fd is open and the flags are passed by value
+    unsafe { tun_set_offload(file.as_raw_fd(), TUN_F_CSUM | TUN_F_TSO4) }?;
+    Ok(file)
+}
+
+fn open_queue(name: &str, flags: libc::c_int) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")?;
+
+    // SAFETY: ifreq is plain data, all zeroes is a valid value
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    req.ifr_ifru.ifru_flags = flags as libc::c_short;
+
+    // SAFETY: the fd is open and req outlives the call
+    unsafe { tun_set_iff(file.as_raw_fd(), &req) }?;
+    Ok(file)
+}
+
+impl AsRawFd for TunQueue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl NetDevice for TunQueue {
+    fn send(&self, frame: &[u8]) -> io::Result<usize> {
+        (&self.file).write(frame)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.file).read(buf)
+    }
+
+    fn offloads(&self) -> Offloads {
+        self.offloads
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+// one end of a virtual wire, whatever is sent on one end is received on the other
+#[derive(Debug)]
+pub struct MemoryDevice {
+    sock: UnixDatagram,
+    offloads: Offloads,
+    mtu: usize,
+}
+
+impl MemoryDevice {
+    pub fn pair() -> io::Result<(MemoryDevice, MemoryDevice)> {
+        MemoryDevice::pair_with(Offloads::default(), DEFAULT_MTU)
+    }
+
+    // both ends claim the offloads, so the stacks on them exchange frames
+    // with virtio-net headers, partial checksums and super segments
+    pub fn pair_with_offloads(offloads: Offloads) -> io::Result<(MemoryDevice, MemoryDevice)> {
+        MemoryDevice::pair_with(offloads, DEFAULT_MTU)
+    }
+
+    // a link that carries IP packets of up to `mtu` bytes
+    pub fn pair_with_mtu(mtu: usize) -> io::Result<(MemoryDevice, MemoryDevice)> {
+        MemoryDevice::pair_with(Offloads::default(), mtu)
+    }
+
+    fn pair_with(offloads: Offloads, mtu: usize) -> io::Result<(MemoryDevice, MemoryDevice)> {
+        let (a, b) = UnixDatagram::pair()?;
+        Ok((
+            MemoryDevice {
+                sock: a,
+                offloads,
+                mtu,
+            },
+            MemoryDevice {
+                sock: b,
+                offloads,
+                mtu,
+            },
+        ))
+    }
+}
+
+impl AsRawFd for MemoryDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl NetDevice for MemoryDevice {
+    fn send(&self, frame: &[u8]) -> io::Result<usize> {
+        self.sock.send(frame)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.recv(buf)
+    }
+
+    fn offloads(&self) -> Offloads {
+        self.offloads
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
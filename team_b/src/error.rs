@@ -0,0 +1,176 @@
+use std::{fmt, io};
+
+// why a packet from the device was rejected by the header parsers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    // fewer bytes than the 4 byte tun header
+    TunTooShort(usize),
+    // fewer bytes than the fixed 20 byte header
+    Ipv4TooShort(usize),
+    Ipv4BadVersion(u8),
+    // ihl below the 5 words of the fixed header
+    Ipv4BadIhl(u8),
+    // ihl points past the end of the packet
+    Ipv4TruncatedOptions(u8),
+    // total_length is shorter than the header or longer than what was received
+    Ipv4BadTotalLength(u16),
+    Ipv4BadChecksum,
+    // an option with a length that is too short, too long or wrong for its
+    // kind, or a pointer before its data
+    Ipv4BadOption(u8),
+    // loose or strict source routing, which the stack does not accept
+    Ipv4SourceRoute,
+    // a fragment that overlaps another fragment of its datagram without
+    // being an exact duplicate, or a second last fragment that disagrees
+    // about the length
+    Ipv4FragmentOverlap,
+    // a fragment that ends past 65535 bytes or past the end of its datagram
+    Ipv4FragmentTooLong(usize),
+    // a fragment other than the last that does not end on an 8 byte boundary
+    Ipv4FragmentMisaligned,
+    Ipv4TooManyFragments,
+    // a fragment where only whole datagrams are handled
+    Ipv4Fragment,
+    TcpTooShort(usize),
+    // data_offset below the 5 words of the fixed header
+    TcpBadDataOffset(u8),
+    // data_offset points past the end of the segment
+    TcpTruncatedOptions(u8),
+    TcpBadChecksum,
+    // an option with a length that is too short, too long or wrong for its kind
+    TcpBadOption(u8),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::TunTooShort(len) => write!(f, "{len} bytes is too short for a tun frame"),
+            ParseError::Ipv4TooShort(len) => write!(f, "{len} bytes is too short for IPv4"),
+            ParseError::Ipv4BadVersion(version) => write!(f, "IP version {version} is not 4"),
+            ParseError::Ipv4BadIhl(ihl) => write!(f, "IPv4 ihl {ihl} is below 5"),
+            ParseError::Ipv4TruncatedOptions(ihl) => {
+                write!(f, "IPv4 ihl {ihl} is longer than the packet")
+            }
+            ParseError::Ipv4BadTotalLength(len) => {
+                write!(f, "IPv4 total length {len} does not match the packet")
+            }
+            ParseError::Ipv4BadChecksum => write!(f, "invalid IPv4 header checksum"),
+            ParseError::Ipv4BadOption(kind) => write!(f, "malformed IPv4 option of kind {kind}"),
+            ParseError::Ipv4SourceRoute => write!(f, "source routed IPv4 datagram"),
+            ParseError::Ipv4FragmentOverlap => write!(f, "overlapping IPv4 fragments"),
+            ParseError::Ipv4FragmentTooLong(end) => {
+                write!(
+                    f,
+                    "IPv4 fragment ends at {end}, past the end of the datagram"
+                )
+            }
+            ParseError::Ipv4FragmentMisaligned => {
+                write!(f, "IPv4 fragment is not a multiple of 8 bytes")
+            }
+            ParseError::Ipv4TooManyFragments => write!(f, "too many IPv4 fragments"),
+            ParseError::Ipv4Fragment => write!(f, "IPv4 fragment outside of reassembly"),
+            ParseError::TcpTooShort(len) => write!(f, "{len} bytes is too short for TCP"),
+            ParseError::TcpBadDataOffset(offset) => {
+                write!(f, "TCP data offset {offset} is below 5")
+            }
+            ParseError::TcpTruncatedOptions(offset) => {
+                write!(f, "TCP data offset {offset} is longer than the segment")
+            }
+            ParseError::TcpBadChecksum => write!(f, "invalid TCP checksum"),
+            ParseError::TcpBadOption(kind) => write!(f, "malformed TCP option of kind {kind}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    // a packet from the device that could not be parsed
+    Malformed(ParseError),
+    InvalidInput(&'static str),
+    ConnectionReset,
+    ConnectionRefused,
+    TimedOut,
+    AddrInUse,
+    NotConnected,
+    BrokenPipe,
+    WouldBlock,
+    // reading from or writing to the link device failed
+    Device(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Malformed(what) => write!(f, "malformed packet: {what}"),
+            Error::InvalidInput(what) => write!(f, "invalid input: {what}"),
+            Error::ConnectionReset => write!(f, "connection reset by peer"),
+            Error::ConnectionRefused => write!(f, "connection refused"),
+            Error::TimedOut => write!(f, "operation timed out"),
+            Error::AddrInUse => write!(f, "address already in use"),
+            Error::NotConnected => write!(f, "not connected"),
+            Error::BrokenPipe => write!(f, "connection is closed for writing"),
+            Error::WouldBlock => write!(f, "operation would block"),
+            Error::Device(e) => write!(f, "device error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Device(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Malformed(_) => io::ErrorKind::InvalidData,
+            Error::InvalidInput(_) => io::ErrorKind::InvalidInput,
+            Error::ConnectionReset => io::ErrorKind::ConnectionReset,
+            Error::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            Error::TimedOut => io::ErrorKind::TimedOut,
+            Error::AddrInUse => io::ErrorKind::AddrInUse,
+            Error::NotConnected => io::ErrorKind::NotConnected,
+            Error::BrokenPipe => io::ErrorKind::BrokenPipe,
+            Error::WouldBlock => io::ErrorKind::WouldBlock,
+            Error::Device(e) => e.kind(),
+        }
+    }
+}
+
+// the original Error can be recovered with io::Error::into_inner
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Device(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+
+        Error::Device(e)
+    }
+}
+
+impl From<nix::Error> for Error {
+    fn from(e: nix::Error) -> Self {
+        Error::Device(e.into())
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Malformed(e)
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::Instant,
+};
+
+// what blocking calls on one connection or listener wait on. The processing
+// thread bumps the generation whenever it has changed the connection, a
+// waiter remembers the generation before looking at the connection so that a
+// change in between is not missed
+#[derive(Debug, Default)]
+pub(crate) struct Events {
+    generation: Mutex<u64>,
+    changed: Condvar,
+    // the poll calls waiting on this connection, with its index in each
+    pub(crate) pollers: Mutex<Vec<(Arc<Poller>, usize)>>,
+}
+
+impl Events {
+    pub(crate) fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    pub(crate) fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.changed.notify_all();
+
+        for (poller, index) in self.pollers.lock().unwrap().iter() {
+            poller.mark(*index);
+        }
+    }
+
+    // waits until the generation is no longer `seen`, returns false once the
+    // deadline has passed
+    pub(crate) fn wait(&self, seen: u64, deadline: Option<Instant>) -> bool {
+        let mut generation = self.generation.lock().unwrap();
+
+        while *generation == seen {
+            generation = match deadline {
+                None => self.changed.wait(generation).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return false;
+                    }
+                    self.changed.wait_timeout(generation, timeout).unwrap().0
+                }
+            };
+        }
+
+        true
+    }
+
+    pub(crate) fn register(&self, poller: &Arc<Poller>, index: usize) {
+        self.pollers.lock().unwrap().push((poller.clone(), index));
+    }
+
+    pub(crate) fn unregister(&self, poller: &Arc<Poller>) {
+        self.pollers
+            .lock()
+            .unwrap()
+            .retain(|(registered, _)| !Arc::ptr_eq(registered, poller));
+    }
+}
+
+// one ConnectionManager::poll call, told which of its entries have changed
+#[derive(Debug, Default)]
+pub(crate) struct Poller {
+    changed: Mutex<Vec<usize>>,
+    wakeup: Condvar,
+}
+
+impl Poller {
+    fn mark(&self, index: usize) {
+        self.changed.lock().unwrap().push(index);
+        self.wakeup.notify_one();
+    }
+
+    // waits until some entry has changed and returns the changed ones, empty
+    // once the deadline has passed
+    pub(crate) fn wait(&self, deadline: Option<Instant>) -> Vec<usize> {
+        let mut changed = self.changed.lock().unwrap();
+
+        while changed.is_empty() {
+            changed = match deadline {
+                None => self.wakeup.wait(changed).unwrap(),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Vec::new();
+                    }
+                    self.wakeup.wait_timeout(changed, timeout).unwrap().0
+                }
+            };
+        }
+
+        std::mem::take(&mut *changed)
+    }
+}
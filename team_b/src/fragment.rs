@@ -0,0 +1,515 @@
+// IPv4 fragmentation, https://datatracker.ietf.org/doc/html/rfc791#section-3.2
+// Incoming fragments are collected per (source, destination, identification,
+// protocol) until their datagram is complete. A datagram that is still
+// missing fragments after TIMEOUT is dropped, and so are the oldest ones
+// when all fragments together would take more than MEMORY. Fragments that
+// overlap drop their whole datagram, like linux does, as they only come
+// from attacks on reassembly; exact duplicates are ignored
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    error::{Error, ParseError, Result},
+    ipv4::IPv4Header,
+    utils::set_u16_be,
+};
+
+// how long the fragments of a datagram are kept, ipfrag_time of linux
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+// for all datagrams together, ipfrag_high_thresh of linux
+pub const MEMORY: usize = 4 * 1024 * 1024;
+
+// what a fragment costs besides its bytes, so that tiny fragments cannot
+// make for a huge number of datagrams
+const OVERHEAD: usize = 128;
+
+// per datagram, enough for 64 KiB over the 576 bytes MTU that every host
+// has to accept
+pub const MAX_FRAGMENTS: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Key {
+    source_ip: u32,
+    dest_ip: u32,
+    identification: u16,
+    protocol: u8,
+}
+
+#[derive(Debug)]
+struct Datagram {
+    // of the first fragment, once it has arrived
+    header: Option<Vec<u8>>,
+    // (offset, payload), sorted by offset and not overlapping
+    fragments: Vec<(usize, Vec<u8>)>,
+    // the length of the payload, known once the last fragment has arrived
+    len: Option<usize>,
+    // counted against MEMORY
+    memory: usize,
+    deadline: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    datagrams: HashMap<Key, Datagram>,
+    memory: usize,
+    // the earliest deadline, expire() has nothing to do before it
+    next_expiry: Option<Instant>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    // `packet` is one fragment, its header has been checked by the caller.
+    // Returns the whole datagram once its last missing fragment arrives, with
+    // the header of the first fragment, so that it parses like a datagram
+    // that was never fragmented
+    pub fn insert(&mut self, packet: &[u8], now: Instant) -> Result<Option<Vec<u8>>> {
+        self.expire(now);
+
+        let (ip, payload) = IPv4Header::new(packet)?;
+        let key = Key {
+            source_ip: ip.source_ip,
+            dest_ip: ip.dest_ip,
+            identification: ip.identification,
+            protocol: ip.protocol,
+        };
+        let offset = ip.fragment_offset as usize * 8;
+        let end = offset + payload.len();
+        let last = ip.flags & IPv4Header::MORE_FRAGMENTS == 0;
+
+        if end + ip.size() > u16::MAX as usize {
+            self.remove(&key);
+            return Err(ParseError::Ipv4FragmentTooLong(end).into());
+        }
+        if !last && (payload.is_empty() || payload.len() % 8 != 0) {
+            self.remove(&key);
+            return Err(ParseError::Ipv4FragmentMisaligned.into());
+        }
+
+        let header = if offset == 0 { ip.size() } else { 0 };
+        let memory = payload.len() + header + OVERHEAD;
+        if !self.make_room(&key, memory) {
+            warn!("no memory left for IPv4 fragments, dropping the datagram");
+            self.remove(&key);
+            return Ok(None);
+        }
+
+        let datagram = self.datagrams.entry(key).or_insert_with(|| Datagram {
+            header: None,
+            fragments: Vec::new(),
+            len: None,
+            memory: 0,
+            deadline: now + TIMEOUT,
+        });
+        self.next_expiry = Some(match self.next_expiry {
+            Some(at) => at.min(datagram.deadline),
+            None => datagram.deadline,
+        });
+
+        match datagram.add(offset, payload, last) {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            Err(e) => {
+                self.remove(&key);
+                return Err(e);
+            }
+        }
+        if offset == 0 {
+            datagram.header = Some(packet[..ip.size()].to_vec());
+        }
+        datagram.memory += memory;
+        self.memory += memory;
+
+        if !datagram.complete() {
+            return Ok(None);
+        }
+        let datagram = self.remove(&key).unwrap();
+        Ok(datagram.assemble())
+    }
+
+    // drops the datagrams whose deadline has passed
+    pub fn expire(&mut self, now: Instant) {
+        if self.next_expiry.is_none_or(|at| at > now) {
+            return;
+        }
+
+        let mut memory = self.memory;
+        self.datagrams.retain(|_, datagram| {
+            let keep = datagram.deadline > now;
+            if !keep {
+                debug!("IPv4 reassembly timed out, dropping the datagram");
+                memory -= datagram.memory;
+            }
+            keep
+        });
+        self.memory = memory;
+        self.next_expiry = self.datagrams.values().map(|d| d.deadline).min();
+    }
+
+    // bytes held by incomplete datagrams, at most MEMORY
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    // drops the oldest datagrams other than `key` until `memory` more bytes
+    // fit, false if they do not fit even then
+    fn make_room(&mut self, key: &Key, memory: usize) -> bool {
+        while self.memory + memory > MEMORY {
+            let oldest = self
+                .datagrams
+                .iter()
+                .filter(|(other, _)| *other != key)
+                .min_by_key(|(_, datagram)| datagram.deadline)
+                .map(|(other, _)| *other);
+            match oldest {
+                Some(oldest) => {
+                    self.remove(&oldest);
+                }
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Datagram> {
+        let datagram = self.datagrams.remove(key)?;
+        self.memory -= datagram.memory;
+        Some(datagram)
+    }
+}
+
+impl Datagram {
+    // false for an exact duplicate, which changes nothing
+    fn add(&mut self, offset: usize, payload: &[u8], last: bool) -> Result<bool> {
+        let end = offset + payload.len();
+        if let Some(len) = self.len {
+            if end > len || (last && end != len) {
+                return Err(ParseError::Ipv4FragmentOverlap.into());
+            }
+        }
+        if last
+            && self
+                .fragments
+                .last()
+                .is_some_and(|(o, p)| o + p.len() > end)
+        {
+            return Err(ParseError::Ipv4FragmentTooLong(end).into());
+        }
+
+        // the first fragment that starts at or after this one
+        let index = self.fragments.partition_point(|(o, _)| *o < offset);
+        if let Some((o, p)) = self.fragments.get(index) {
+            if *o == offset && p[..] == *payload {
+                return Ok(false);
+            }
+            if *o < end {
+                return Err(ParseError::Ipv4FragmentOverlap.into());
+            }
+        }
+        if index > 0 {
+            let (o, p) = &self.fragments[index - 1];
+            if o + p.len() > offset {
+                return Err(ParseError::Ipv4FragmentOverlap.into());
+            }
+        }
+        if self.fragments.len() == MAX_FRAGMENTS {
+            return Err(ParseError::Ipv4TooManyFragments.into());
+        }
+
+        self.fragments.insert(index, (offset, payload.to_vec()));
+        if last {
+            self.len = Some(end);
+        }
+        Ok(true)
+    }
+
+    // the fragments do not overlap and end before len, so if their lengths
+    // add up to it there is no gap between them
+    fn complete(&self) -> bool {
+        let received: usize = self.fragments.iter().map(|(_, p)| p.len()).sum();
+        self.header.is_some() && self.len == Some(received)
+    }
+
+    // None if the header of the first fragment makes it longer than 65535
+    fn assemble(self) -> Option<Vec<u8>> {
+        let mut packet = self.header?;
+        let total_length = packet.len() + self.len?;
+        if total_length > u16::MAX as usize {
+            debug!("reassembled IPv4 datagram is too long, dropping it");
+            return None;
+        }
+
+        packet.reserve(self.len?);
+        for (_, payload) in &self.fragments {
+            packet.extend_from_slice(payload);
+        }
+
+        // a whole datagram now: no more fragments, offset 0, and the length
+        // and checksum to match
+        set_u16_be(&mut packet[2..4], total_length as u16);
+        packet[6] &= !(IPv4Header::MORE_FRAGMENTS << 5) & 0b1110_0000;
+        packet[7] = 0;
+        set_u16_be(&mut packet[10..12], 0);
+        let (ip, _) = IPv4Header::new(&packet).ok()?;
+        let checksum = ip.calc_checksum();
+        set_u16_be(&mut packet[10..12], checksum);
+
+        Some(packet)
+    }
+}
+
+// splits `packet`, a whole datagram or a fragment of one, into fragments of
+// at most `mtu` bytes and hands each one to `write` as header and payload.
+// Options without the copied flag only go into the first fragment
+pub fn fragment(
+    packet: &[u8],
+    mtu: usize,
+    identification: u16,
+    mut write: impl FnMut(&[u8], &[u8]),
+) -> Result<()> {
+    let (ip, payload) = IPv4Header::new(packet)?;
+    if ip.flags & IPv4Header::DONT_FRAGMENT != 0 {
+        return Err(Error::InvalidInput("the datagram must not be fragmented"));
+    }
+    if mtu < ip.size() + 8 {
+        return Err(Error::InvalidInput("MTU too small to fragment"));
+    }
+
+    let mut copied = [0; 40];
+    let copied_len = copied_options(&ip, &mut copied);
+    let base = ip.fragment_offset as usize * 8;
+    let mut header = [0; 60];
+
+    let mut offset = 0;
+    loop {
+        let options = match offset {
+            0 => ip.options,
+            _ => &copied[..copied_len],
+        };
+        let header_len = 20 + options.len();
+        let room = (mtu - header_len) / 8 * 8;
+        let len = room.min(payload.len() - offset);
+        let more = offset + len < payload.len();
+
+        let mut fragment = IPv4Header {
+            ihl: header_len as u8 / 4,
+            total_length: (header_len + len) as u16,
+            identification,
+            flags: match more {
+                true => ip.flags | IPv4Header::MORE_FRAGMENTS,
+                false => ip.flags,
+            },
+            fragment_offset: ((base + offset) / 8) as u16,
+            header_checksum: 0,
+            options,
+            ..ip
+        };
+        fragment.header_checksum = fragment.calc_checksum();
+        fragment.serialize_into(&mut header);
+
+        write(&header[..header_len], &payload[offset..offset + len]);
+        offset += len;
+        if !more {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// the options that go into every fragment, padded to a multiple of 4 bytes,
+// returns their length
+fn copied_options(ip: &IPv4Header, copied: &mut [u8; 40]) -> usize {
+    let mut len = 0;
+    for option in ip.parse_options().map_while(Result::ok) {
+        if option.is_copied() {
+            let option = option.serialize();
+            copied[len..len + option.len()].copy_from_slice(&option);
+            len += option.len();
+        }
+    }
+
+    len.div_ceil(4) * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(len: usize) -> Vec<u8> {
+        let ip = IPv4Header {
+            version: 4,
+            ihl: 5,
+            dscp: 0,
+            ecn: 0,
+            total_length: 20 + len as u16,
+            identification: 0x1234,
+            flags: 0,
+            fragment_offset: 0,
+            time_to_live: 64,
+            protocol: 6,
+            header_checksum: 0,
+            source_ip: 0x0a000002,
+            dest_ip: 0x0a000003,
+            options: &[],
+        };
+        let mut packet = ip.serialize()[..20].to_vec();
+        set_u16_be(&mut packet[10..12], ip.calc_checksum());
+        packet.extend((0..len).map(|i| i as u8));
+        packet
+    }
+
+    fn fix_checksum(packet: &mut [u8]) {
+        let (ip, _) = IPv4Header::new(packet).unwrap();
+        let checksum = ip.calc_checksum();
+        set_u16_be(&mut packet[10..12], checksum);
+    }
+
+    fn fragments(packet: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+        let mut fragments = Vec::new();
+        fragment(packet, mtu, 0x1234, |header, payload| {
+            fragments.push([header, payload].concat())
+        })
+        .unwrap();
+        fragments
+    }
+
+    #[test]
+    fn fragments_reassemble_in_any_order() {
+        let packet = datagram(1000);
+        let mut fragments = fragments(&packet, 276);
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|f| f.len() <= 276));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        fragments.swap(0, 3);
+        fragments.swap(1, 2);
+        let last = fragments.pop().unwrap();
+        for fragment in &fragments {
+            assert_eq!(reassembler.insert(fragment, now).unwrap(), None);
+        }
+        // a duplicate changes nothing
+        assert_eq!(reassembler.insert(&fragments[0], now).unwrap(), None);
+
+        assert_eq!(reassembler.insert(&last, now).unwrap(), Some(packet));
+        assert_eq!(reassembler.memory(), 0);
+    }
+
+    #[test]
+    fn overlapping_fragments_drop_the_datagram() {
+        let packet = datagram(32);
+        let fragments = fragments(&packet, 36);
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        reassembler.insert(&fragments[0], now).unwrap();
+
+        // the second fragment moved back by 8 bytes, over the first one
+        let mut overlapping = fragments[1].clone();
+        overlapping[7] -= 1;
+        fix_checksum(&mut overlapping);
+
+        assert!(matches!(
+            reassembler.insert(&overlapping, now),
+            Err(Error::Malformed(ParseError::Ipv4FragmentOverlap))
+        ));
+        assert_eq!(reassembler.memory(), 0);
+        assert_eq!(reassembler.insert(&fragments[1], now).unwrap(), None);
+    }
+
+    #[test]
+    fn incomplete_datagrams_expire() {
+        let fragments = fragments(&datagram(100), 68);
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+
+        reassembler.insert(&fragments[0], now).unwrap();
+        reassembler.expire(now + TIMEOUT - Duration::from_millis(1));
+        assert!(reassembler.memory() > 0);
+        reassembler.expire(now + TIMEOUT);
+        assert_eq!(reassembler.memory(), 0);
+
+        // the rest alone does not make a datagram any more
+        let later = now + TIMEOUT;
+        for fragment in &fragments[1..] {
+            assert_eq!(reassembler.insert(fragment, later).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn memory_is_limited() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+
+        // first fragments of many datagrams that never complete
+        let packet = datagram(60000);
+        for id in 0..200u16 {
+            let mut first = fragments(&packet, 30020).swap_remove(0);
+            set_u16_be(&mut first[4..6], id);
+            fix_checksum(&mut first);
+
+            reassembler.insert(&first, now).unwrap();
+            assert!(reassembler.memory() <= MEMORY);
+        }
+
+        // the oldest were dropped to make room for the newest
+        assert_eq!(
+            reassembler.datagrams.len(),
+            MEMORY / (30000 + 20 + OVERHEAD)
+        );
+    }
+
+    #[test]
+    fn too_long_and_misaligned_fragments() {
+        let mut packet = datagram(16);
+        // the last fragment, ending past 65535
+        packet[6] = 0x1f;
+        packet[7] = 0xff;
+        fix_checksum(&mut packet);
+        let mut reassembler = Reassembler::new();
+        assert!(matches!(
+            reassembler.insert(&packet, Instant::now()),
+            Err(Error::Malformed(ParseError::Ipv4FragmentTooLong(_)))
+        ));
+
+        let mut packet = datagram(15);
+        packet[6] = 0x20;
+        fix_checksum(&mut packet);
+        assert!(matches!(
+            reassembler.insert(&packet, Instant::now()),
+            Err(Error::Malformed(ParseError::Ipv4FragmentMisaligned))
+        ));
+    }
+
+    #[test]
+    fn only_copied_options_are_repeated() {
+        // loose source route (copied) and record route (not copied)
+        let options = [0x83, 3, 4, 7, 3, 4, 0, 0];
+        let base = datagram(40);
+        let mut ip = IPv4Header::new(&base).unwrap().0;
+        ip.ihl = 7;
+        ip.total_length = 68;
+        ip.options = &options;
+
+        let mut copied = [0; 40];
+        let len = copied_options(&ip, &mut copied);
+        assert_eq!(&copied[..len], [0x83, 3, 4, 0]);
+        let mut packet = ip.serialize()[..28].to_vec();
+        packet.extend([0; 40]);
+
+        let fragments = fragments(&packet, 48);
+        let (first, _) = IPv4Header::new(&fragments[0]).unwrap();
+        let (second, _) = IPv4Header::new(&fragments[1]).unwrap();
+        assert_eq!(first.options, options);
+        assert_eq!(second.options, [0x83, 3, 4, 0]);
+    }
+}
@@ -0,0 +1,195 @@
+// a Manager without a device or a processing thread: frames go in through
+// input(), timers run on tick() and replies pile up until output(). Time only
+// passes when advance() is called
+
+use std::{collections::HashMap, net::Ipv4Addr, sync::Arc, time::Duration};
+
+use crate::{
+    clock::VirtualClock,
+    device::{Offloads, DEFAULT_MTU},
+    error::{Error, Result},
+    ipv4::IPv4Header,
+    tcp::{build_tcp_packet, TcpHeader},
+    utils::ConnectionId,
+    Connection, ConnectionKey, Manager, PacketPool, Shared, SharedConnection, State, SEND_BUFFER,
+};
+
+pub const LOCAL_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+pub const LOCAL_PORT: u16 = 8080;
+pub const REMOTE_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+pub struct Stack {
+    mgr: Manager,
+    clock: Arc<VirtualClock>,
+    // accepted and connected connections, by remote port
+    conns: HashMap<u16, ConnectionId>,
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Stack::new()
+    }
+}
+
+impl Stack {
+    // listening on LOCAL_IP:LOCAL_PORT
+    pub fn new() -> Stack {
+        let clock = Arc::new(VirtualClock::new());
+        let mut mgr = Manager::new(
+            clock.clone(),
+            Arc::new(PacketPool::new(4 + DEFAULT_MTU)),
+            Offloads::default(),
+            DEFAULT_MTU,
+        );
+        let ip = LOCAL_IP.into();
+        mgr.listen.insert(
+            (ip, LOCAL_PORT),
+            Shared::new(Connection::new(ip, LOCAL_PORT)),
+        );
+
+        Stack {
+            mgr,
+            clock,
+            conns: HashMap::new(),
+        }
+    }
+
+    pub fn input(&mut self, frame: &[u8]) -> Result<()> {
+        self.mgr.on_packet(frame)
+    }
+
+    pub fn tick(&mut self) {
+        self.mgr.on_tick();
+    }
+
+    pub fn advance(&mut self, by: Duration) {
+        self.clock.advance(by);
+    }
+
+    pub fn output(&mut self) -> Vec<Vec<u8>> {
+        self.mgr.outgoing.take()
+    }
+
+    // an active open towards REMOTE_IP, the SYN goes out on the next tick
+    pub fn connect(&mut self, remote_port: u16) -> Result<()> {
+        let local_ip = LOCAL_IP.into();
+        let remote_ip = REMOTE_IP.into();
+        let port = self
+            .mgr
+            .ephemeral_port(local_ip, Some((remote_ip, remote_port)))?;
+
+        let mut conn = Connection::new(local_ip, port);
+        conn.client_ip = remote_ip;
+        conn.client_port = remote_port;
+        conn.state = State::SynSent;
+        conn.handles = 1;
+
+        self.conns.insert(remote_port, conn.id());
+        self.mgr.insert(conn);
+        Ok(())
+    }
+
+    pub fn accept(&mut self) -> Result<()> {
+        let conn = self.mgr.take_accepted(LOCAL_IP.into(), LOCAL_PORT)?;
+        self.conns.insert(conn.client_port, conn.id());
+        self.mgr.insert(conn);
+        Ok(())
+    }
+
+    pub fn write(&mut self, remote_port: u16, buf: &[u8]) -> Result<usize> {
+        let written = self.conn(remote_port)?.lock().unwrap().write(buf)?;
+        self.mgr.mark_ready(&self.conns[&remote_port]);
+        Ok(written)
+    }
+
+    pub fn read(&mut self, remote_port: u16, buf: &mut [u8]) -> Result<usize> {
+        let conn = self.conn(remote_port)?;
+        let mut conn = conn.lock().unwrap();
+        let read = conn.read(buf)?;
+        if conn.window_update {
+            self.mgr.mark_ready(&self.conns[&remote_port]);
+        }
+        Ok(read)
+    }
+
+    pub fn close(&mut self, remote_port: u16) -> Result<()> {
+        self.conn(remote_port)?.lock().unwrap().closing = true;
+        self.mgr.mark_ready(&self.conns[&remote_port]);
+        Ok(())
+    }
+
+    pub fn abort(&mut self, remote_port: u16) -> Result<()> {
+        let id = self.conns.remove(&remote_port).ok_or(Error::NotConnected)?;
+        let conn = self
+            .mgr
+            .remove(&ConnectionKey::Conn(id))
+            .ok_or(Error::NotConnected)?;
+        conn.lock().unwrap().abort(&mut self.mgr.outgoing);
+
+        Ok(())
+    }
+
+    // a frame from REMOTE_IP:remote_port with seq and ack relative to the
+    // next sequence numbers the stack expects, so small deltas get past the
+    // window checks. Connections that were not accepted yet use the listener
+    pub fn segment(
+        &self,
+        remote_port: u16,
+        flags: u8,
+        seq_delta: u32,
+        ack_delta: u32,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let listener = self.mgr.listen.get(&(LOCAL_IP.into(), LOCAL_PORT));
+        let conn = self
+            .conns
+            .get(&remote_port)
+            .and_then(|id| self.mgr.conns.get(id))
+            .or(listener);
+        let (recv_seq, send_seq, local_port) = conn
+            .map(|conn| {
+                let conn = conn.lock().unwrap();
+                (conn.recv_seq, conn.send_seq, conn.server_port)
+            })
+            .unwrap_or((0, 0, LOCAL_PORT));
+
+        // build_tcp_packet sends from ip_dst to ip_src
+        let id = ConnectionId {
+            ip_src: LOCAL_IP.into(),
+            ip_dst: REMOTE_IP.into(),
+            port_src: local_port,
+            port_dst: remote_port,
+        };
+
+        build_tcp_packet(
+            &id,
+            flags,
+            recv_seq.wrapping_add(seq_delta),
+            send_seq.wrapping_add(ack_delta),
+            payload,
+        )
+    }
+
+    // panics if the stack got into a state it should never be in
+    pub fn check_invariants(&self) {
+        for frame in &self.mgr.outgoing.frames {
+            let (ip, data) = IPv4Header::new(&frame[4..]).expect("sent an invalid IPv4 header");
+            assert_eq!(ip.header_checksum, ip.calc_checksum());
+
+            let (tcp, text) = TcpHeader::new(data).expect("sent an invalid TCP header");
+            assert_eq!(
+                tcp.checksum,
+                tcp.calc_checksum(ip.source_ip, ip.dest_ip, tcp.size() + text.len(), text)
+            );
+        }
+
+        for conn in self.mgr.conns.values() {
+            assert!(conn.lock().unwrap().send_queue.len() <= SEND_BUFFER);
+        }
+    }
+
+    fn conn(&self, remote_port: u16) -> Result<SharedConnection> {
+        let id = self.conns.get(&remote_port).ok_or(Error::NotConnected)?;
+        self.mgr.conns.get(id).cloned().ok_or(Error::NotConnected)
+    }
+}
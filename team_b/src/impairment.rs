@@ -0,0 +1,232 @@
+// a device that sits between the stack and another device and makes the link
+// worse: drops, delays, reorders, duplicates and corrupts frames, and limits
+// the bandwidth. Decisions come from a seeded rng, so a given seed makes the
+// same decisions for the same sequence of frames
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    time::{Duration, Instant},
+};
+
+use log::error;
+use nix::poll::{poll, PollFd, PollFlags};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::device::{NetDevice, Offloads};
+
+// what happens to frames going in one direction, probabilities are in 0.0..=1.0
+#[derive(Clone, Debug, Default)]
+pub struct Impairment {
+    pub loss: f64,
+    pub duplicate: f64,
+    // flips one random bit after the tun header
+    pub corrupt: f64,
+    // a reordered frame is held back for reorder_delay on top of the delay
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    pub delay: Duration,
+    // added to the delay, uniformly distributed in 0..=jitter
+    pub jitter: Duration,
+    // bytes per second, None is unlimited
+    pub rate: Option<u64>,
+}
+
+// everything the stack sends goes through `egress` before reaching the device,
+// everything the device receives goes through `ingress` before reaching the stack
+#[derive(Clone, Debug, Default)]
+pub struct ImpairmentConfig {
+    pub egress: Impairment,
+    pub ingress: Impairment,
+    pub seed: u64,
+}
+
+// the stack talks to one end of a socket pair, a thread moves frames between
+// the other end and the real device. The thread stops once delivering a frame
+// fails, e.g. because the stack side was dropped
+#[derive(Debug)]
+pub struct ImpairedDevice {
+    sock: UnixDatagram,
+    // the ones of the real device, frames are passed through unchanged
+    offloads: Offloads,
+    mtu: usize,
+}
+
+impl ImpairedDevice {
+    pub fn new(device: impl NetDevice, config: ImpairmentConfig) -> io::Result<ImpairedDevice> {
+        for imp in [&config.egress, &config.ingress] {
+            let probabilities = [imp.loss, imp.duplicate, imp.corrupt, imp.reorder];
+            if !probabilities.iter().all(|p| (0.0..=1.0).contains(p)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "probabilities must be between 0 and 1",
+                ));
+            }
+            // nothing would ever get through
+            if imp.rate == Some(0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the rate must be more than 0 bytes per second",
+                ));
+            }
+        }
+
+        let (sock, shim) = UnixDatagram::pair()?;
+        let offloads = device.offloads();
+        let mtu = device.mtu();
+
+        std::thread::spawn(move || {
+            if let Err(e) = run(device, shim, config) {
+                error!("impairment thread stopped: {e}");
+            }
+        });
+
+        Ok(ImpairedDevice {
+            sock,
+            offloads,
+            mtu,
+        })
+    }
+}
+
+impl AsRawFd for ImpairedDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl NetDevice for ImpairedDevice {
+    fn send(&self, frame: &[u8]) -> io::Result<usize> {
+        self.sock.send(frame)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.recv(buf)
+    }
+
+    fn offloads(&self) -> Offloads {
+        self.offloads
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+}
+
+// one direction of the link
+struct Direction {
+    impairment: Impairment,
+    rng: StdRng,
+    // when the last scheduled frame has finished "transmitting", for the rate limit
+    busy_until: Instant,
+}
+
+impl Direction {
+    fn new(impairment: Impairment, seed: u64) -> Direction {
+        Direction {
+            impairment,
+            rng: StdRng::seed_from_u64(seed),
+            busy_until: Instant::now(),
+        }
+    }
+
+    // when each copy of the frame should be delivered, if at all
+    fn schedule(&mut self, frame: &mut [u8], now: Instant) -> Vec<Instant> {
+        let imp = &self.impairment;
+
+        if self.rng.gen_bool(imp.loss) {
+            return Vec::new();
+        }
+
+        if frame.len() > 4 && self.rng.gen_bool(imp.corrupt) {
+            let bit = self.rng.gen_range(32..frame.len() * 8);
+            frame[bit / 8] ^= 1 << (bit % 8);
+        }
+
+        let copies = if self.rng.gen_bool(imp.duplicate) {
+            2
+        } else {
+            1
+        };
+        let mut times = Vec::new();
+
+        for _ in 0..copies {
+            let mut at = now + imp.delay + imp.jitter.mul_f64(self.rng.gen());
+            if self.rng.gen_bool(imp.reorder) {
+                at += imp.reorder_delay;
+            }
+
+            if let Some(rate) = imp.rate {
+                let start = at.max(self.busy_until);
+                self.busy_until = start + Duration::from_secs_f64(frame.len() as f64 / rate as f64);
+                at = self.busy_until;
+            }
+
+            times.push(at);
+        }
+
+        times
+    }
+}
+
+// frames waiting for their delivery time, `to_device` is false for ingress
+type Pending = BinaryHeap<Reverse<(Instant, u64, bool, Vec<u8>)>>;
+
+fn run(device: impl NetDevice, shim: UnixDatagram, config: ImpairmentConfig) -> io::Result<()> {
+    let mut egress = Direction::new(config.egress, config.seed);
+    let mut ingress = Direction::new(config.ingress, config.seed.wrapping_add(1));
+    let mut pending = Pending::new();
+    // keeps frames scheduled for the same instant in arrival order
+    let mut counter = 0;
+    let mut buf = vec![0; device.offloads().max_frame(device.mtu())];
+
+    loop {
+        let now = Instant::now();
+        while pending.peek().is_some_and(|Reverse((at, ..))| *at <= now) {
+            let Reverse((_, _, to_device, frame)) = pending.pop().unwrap();
+            if to_device {
+                device.send(&frame)?;
+            } else {
+                shim.send(&frame)?;
+            }
+        }
+
+        // poll takes milliseconds, round up so that we don't spin
+        let timeout = match pending.peek() {
+            Some(Reverse((at, ..))) => {
+                let wait = at.saturating_duration_since(now);
+                wait.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+
+        let mut fds = [
+            PollFd::new(shim.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(device.as_raw_fd(), PollFlags::POLLIN),
+        ];
+        poll(&mut fds, timeout)?;
+
+        let now = Instant::now();
+        for (i, fd) in fds.iter().enumerate() {
+            if !fd.revents().is_some_and(|r| r.contains(PollFlags::POLLIN)) {
+                continue;
+            }
+
+            let (to_device, direction, len) = match i {
+                0 => (true, &mut egress, shim.recv(&mut buf)?),
+                _ => (false, &mut ingress, device.recv(&mut buf)?),
+            };
+
+            let mut frame = buf[..len].to_vec();
+            for at in direction.schedule(&mut frame, now) {
+                pending.push(Reverse((at, counter, to_device, frame.clone())));
+                counter += 1;
+            }
+        }
+    }
+}
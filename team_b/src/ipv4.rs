@@ -1,8 +1,10 @@
-use anyhow::Result;
+use checksum::Checksum;
+
+use crate::error::{ParseError, Result};
 
 use crate::utils::*;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct IPv4Header<'a> {
     pub version: u8,
     pub ihl: u8,
@@ -21,41 +23,72 @@ pub struct IPv4Header<'a> {
 }
 
 impl<'a> IPv4Header<'a> {
+    // bits of `flags`, the highest one is reserved
+    pub const DONT_FRAGMENT: u8 = 0b010;
+    pub const MORE_FRAGMENTS: u8 = 0b001;
+
     // https://en.wikipedia.org/wiki/Internet_Protocol_version_4#Header
-    pub fn new(data: &'a [u8]) -> Result<(IPv4Header<'a>, &[u8])> {
+    // the returned payload ends at total_length, anything after it is link padding
+    pub fn new(data: &'a [u8]) -> Result<(IPv4Header<'a>, &'a [u8])> {
+        if data.len() < 20 {
+            return Err(ParseError::Ipv4TooShort(data.len()).into());
+        }
+
+        let version = data[0] >> 4;
+        if version != 4 {
+            return Err(ParseError::Ipv4BadVersion(version).into());
+        }
+
         let ihl = data[0] & 0b00001111;
+        let header_len = ihl as usize * 4;
+        if ihl < 5 {
+            return Err(ParseError::Ipv4BadIhl(ihl).into());
+        }
+        if header_len > data.len() {
+            return Err(ParseError::Ipv4TruncatedOptions(ihl).into());
+        }
+
+        let total_length = u16::from_be_bytes([data[2], data[3]]);
+        if (total_length as usize) < header_len || total_length as usize > data.len() {
+            return Err(ParseError::Ipv4BadTotalLength(total_length).into());
+        }
 
         Ok((
             IPv4Header {
-                version: data[0] >> 4,
+                version,
                 ihl,
                 dscp: data[1] >> 2,
                 ecn: data[1] & 0b00000011,
-                total_length: u16::from_be_bytes(data[2..4].try_into()?),
-                identification: u16::from_be_bytes(data[4..6].try_into()?),
+                total_length,
+                identification: u16::from_be_bytes([data[4], data[5]]),
                 flags: data[6] >> 5,
-                fragment_offset: u16::from_be_bytes(data[6..8].try_into()?) & 0b0001111111111111,
+                fragment_offset: u16::from_be_bytes([data[6], data[7]]) & 0b0001111111111111,
                 time_to_live: data[8],
                 protocol: data[9],
-                header_checksum: u16::from_be_bytes(data[10..12].try_into()?),
-                source_ip: u32::from_be_bytes(data[12..16].try_into()?),
-                dest_ip: u32::from_be_bytes(data[16..20].try_into()?),
-                options: &data[20..ihl as usize * 4],
+                header_checksum: u16::from_be_bytes([data[10], data[11]]),
+                source_ip: u32::from_be_bytes([data[12], data[13], data[14], data[15]]),
+                dest_ip: u32::from_be_bytes([data[16], data[17], data[18], data[19]]),
+                options: &data[20..header_len],
             },
-            &data[ihl as usize * 4..],
+            &data[header_len..total_length as usize],
         ))
     }
 
     pub fn serialize(&self) -> Vec<u8> {
-        let mut data = vec![0; 56];
+        let mut data = vec![0; 60];
+        self.serialize_into(&mut data);
+        data
+    }
 
+    // writes the size() bytes of the header to the start of `data`
+    pub fn serialize_into(&self, data: &mut [u8]) {
         data[0] = self.ihl + (self.version << 4);
         data[1] = self.ecn + (self.dscp << 2);
         set_u16_be(&mut data[2..4], self.total_length);
         set_u16_be(&mut data[4..6], self.identification);
         set_u16_be(
             &mut data[6..8],
-            (self.fragment_offset + (self.flags as u16)) << 13,
+            ((self.flags as u16) << 13) | self.fragment_offset,
         );
         data[8] = self.time_to_live;
         data[9] = self.protocol;
@@ -63,33 +96,361 @@ impl<'a> IPv4Header<'a> {
         set_u32_be(&mut data[12..16], self.source_ip);
         set_u32_be(&mut data[16..20], self.dest_ip);
         data[20..self.size()].copy_from_slice(self.options);
+    }
 
-        data
+    pub fn parse_options(&self) -> Ipv4Options<'a> {
+        Ipv4Options { data: self.options }
+    }
+
+    // what the stack does with each option of a datagram addressed to it,
+    // see Ipv4Option. A malformed option drops the datagram, like a
+    // parameter problem would in a stack that sends ICMP
+    pub fn check_options(&self) -> Result<()> {
+        for option in self.parse_options() {
+            match option? {
+                Ipv4Option::LooseSourceRoute(..) | Ipv4Option::StrictSourceRoute(..) => {
+                    return Err(ParseError::Ipv4SourceRoute.into())
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
     }
 
     pub fn calc_checksum(&self) -> u16 {
-        let mut data = self.serialize();
-        let mut sum = 0;
+        let mut data = [0; 60];
+        self.serialize_into(&mut data);
+
+        // skips the checksum field
+        Checksum::new()
+            .add_bytes(&data[..10])
+            .add_bytes(&data[12..self.size()])
+            .finish()
+    }
+
+    pub fn size(&self) -> usize {
+        self.ihl as usize * 4
+    }
+
+    // only a part of the datagram, see fragment::Reassembler
+    pub fn is_fragment(&self) -> bool {
+        self.flags & IPv4Header::MORE_FRAGMENTS != 0 || self.fragment_offset != 0
+    }
+}
+
+// https://www.iana.org/assignments/ip-parameters/ip-parameters.xhtml#ip-parameters-1
+// the kind's highest bit says whether the option is copied into every
+// fragment. Options are only parsed and never sent by the stack, there is no
+// ICMP to answer record route or timestamp requests in echo replies
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ipv4Option<'a> {
+    Nop,
+    // security, compartments, handling restrictions, transmission control
+    // code. Ignored, it was replaced by rfc1108
+    Security(u16, u16, u16, [u8; 3]),
+    // pointer, route data of 4 byte addresses. Datagrams with a source route
+    // are dropped like linux does without accept_source_route, otherwise the
+    // route would have to be reversed for the replies of a connection
+    LooseSourceRoute(u8, &'a [u8]),
+    StrictSourceRoute(u8, &'a [u8]),
+    // pointer, route data. Ignored, replies are not routed back along it
+    RecordRoute(u8, &'a [u8]),
+    // ignored, obsolete according to rfc6814
+    StreamId(u16),
+    // pointer, overflow, flag, timestamps with or without addresses. Ignored,
+    // the destination does not add its own timestamp
+    Timestamp(u8, u8, u8, &'a [u8]),
+    // ignored as rfc1122 requires
+    Unknown(u8, &'a [u8]),
+}
+
+impl Ipv4Option<'_> {
+    // not an option but the end of the list, see Ipv4Options
+    pub const END: u8 = 0;
+    pub const NOP: u8 = 1;
+    pub const SECURITY: u8 = 130;
+    pub const LOOSE_SOURCE_ROUTE: u8 = 131;
+    pub const STRICT_SOURCE_ROUTE: u8 = 137;
+    pub const RECORD_ROUTE: u8 = 7;
+    pub const STREAM_ID: u8 = 136;
+    pub const TIMESTAMP: u8 = 68;
+
+    pub fn kind(&self) -> u8 {
+        match self {
+            Ipv4Option::Nop => Ipv4Option::NOP,
+            Ipv4Option::Security(..) => Ipv4Option::SECURITY,
+            Ipv4Option::LooseSourceRoute(..) => Ipv4Option::LOOSE_SOURCE_ROUTE,
+            Ipv4Option::StrictSourceRoute(..) => Ipv4Option::STRICT_SOURCE_ROUTE,
+            Ipv4Option::RecordRoute(..) => Ipv4Option::RECORD_ROUTE,
+            Ipv4Option::StreamId(_) => Ipv4Option::STREAM_ID,
+            Ipv4Option::Timestamp(..) => Ipv4Option::TIMESTAMP,
+            Ipv4Option::Unknown(kind, _) => *kind,
+        }
+    }
+
+    // repeated in every fragment of a datagram, not only the first
+    pub fn is_copied(&self) -> bool {
+        self.kind() & 0x80 != 0
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let kind = self.kind();
+        match self {
+            Ipv4Option::Nop => vec![kind],
+            Ipv4Option::Security(security, compartments, handling, tcc) => [
+                &[kind, 11][..],
+                &security.to_be_bytes(),
+                &compartments.to_be_bytes(),
+                &handling.to_be_bytes(),
+                tcc,
+            ]
+            .concat(),
+            Ipv4Option::LooseSourceRoute(pointer, route)
+            | Ipv4Option::StrictSourceRoute(pointer, route)
+            | Ipv4Option::RecordRoute(pointer, route) => {
+                [&[kind, route.len() as u8 + 3, *pointer][..], route].concat()
+            }
+            Ipv4Option::StreamId(id) => [&[kind, 4][..], &id.to_be_bytes()].concat(),
+            Ipv4Option::Timestamp(pointer, overflow, flag, data) => [
+                &[kind, data.len() as u8 + 4, *pointer, overflow << 4 | flag][..],
+                data,
+            ]
+            .concat(),
+            Ipv4Option::Unknown(kind, value) => {
+                [&[*kind, value.len() as u8 + 2][..], value].concat()
+            }
+        }
+    }
+}
 
-        if data.len() % 2 != 0 {
-            data.push(0);
+// stops at the end of option list, or after the first malformed option.
+// There is no End variant on purpose: what follows the end of option list
+// is padding up to the header length, it is never looked at and may hold
+// anything, so the iterator just ends there
+pub struct Ipv4Options<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Ipv4Options<'a> {
+    type Item = Result<Ipv4Option<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&kind, rest) = self.data.split_first()?;
+        match kind {
+            Ipv4Option::END => {
+                self.data = &[];
+                return None;
+            }
+            Ipv4Option::NOP => {
+                self.data = rest;
+                return Some(Ok(Ipv4Option::Nop));
+            }
+            _ => {}
         }
 
-        for i in (0..data.len()).step_by(2) {
-            if i == 10 {
-                continue;
-            }; // ignore the checksum field
+        // the length includes the kind and length bytes
+        let len = match rest.first() {
+            Some(&len) if len >= 2 && len as usize <= self.data.len() => len as usize,
+            _ => {
+                self.data = &[];
+                return Some(Err(ParseError::Ipv4BadOption(kind).into()));
+            }
+        };
+        let value = &self.data[2..len];
+        self.data = &self.data[len..];
+
+        // pointers count from the start of the option and start at the first
+        // address or timestamp
+        let option = match (kind, value) {
+            (Ipv4Option::SECURITY, &[a, b, c, d, e, f, g, h, i]) => Ipv4Option::Security(
+                u16::from_be_bytes([a, b]),
+                u16::from_be_bytes([c, d]),
+                u16::from_be_bytes([e, f]),
+                [g, h, i],
+            ),
+            (
+                Ipv4Option::LOOSE_SOURCE_ROUTE
+                | Ipv4Option::STRICT_SOURCE_ROUTE
+                | Ipv4Option::RECORD_ROUTE,
+                &[pointer, ref route @ ..],
+            ) if pointer >= 4 && route.len() % 4 == 0 => match kind {
+                Ipv4Option::LOOSE_SOURCE_ROUTE => Ipv4Option::LooseSourceRoute(pointer, route),
+                Ipv4Option::STRICT_SOURCE_ROUTE => Ipv4Option::StrictSourceRoute(pointer, route),
+                _ => Ipv4Option::RecordRoute(pointer, route),
+            },
+            (Ipv4Option::STREAM_ID, &[a, b]) => Ipv4Option::StreamId(u16::from_be_bytes([a, b])),
+            // flag 0 has timestamps only, 1 and 3 address and timestamp pairs
+            (Ipv4Option::TIMESTAMP, &[pointer, flags, ref data @ ..])
+                if pointer >= 5
+                    && match flags & 0x0f {
+                        0 => data.len() % 4 == 0,
+                        1 | 3 => data.len() % 8 == 0,
+                        _ => false,
+                    } =>
+            {
+                Ipv4Option::Timestamp(pointer, flags >> 4, flags & 0x0f, data)
+            }
+            (
+                Ipv4Option::SECURITY
+                | Ipv4Option::LOOSE_SOURCE_ROUTE
+                | Ipv4Option::STRICT_SOURCE_ROUTE
+                | Ipv4Option::RECORD_ROUTE
+                | Ipv4Option::STREAM_ID
+                | Ipv4Option::TIMESTAMP,
+                _,
+            ) => {
+                self.data = &[];
+                return Some(Err(ParseError::Ipv4BadOption(kind).into()));
+            }
+            (kind, value) => Ipv4Option::Unknown(kind, value),
+        };
+
+        Some(Ok(option))
+    }
+}
 
-            sum += u16::from_be_bytes(data[i..i + 2].try_into().unwrap()) as u32;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            sum += sum >> 16;
-            sum &= 0x0000FFFF;
+    fn header(options: &[u8]) -> IPv4Header<'_> {
+        IPv4Header {
+            version: 4,
+            ihl: 5 + options.len() as u8 / 4,
+            dscp: 10,
+            ecn: 1,
+            total_length: 20 + options.len() as u16 + 3,
+            identification: 0x1234,
+            flags: 0b010,
+            fragment_offset: 0x1abc,
+            time_to_live: 64,
+            protocol: 6,
+            header_checksum: 0,
+            source_ip: 0x0a000002,
+            dest_ip: 0x0a000003,
+            options,
         }
+    }
+
+    #[test]
+    fn round_trip() {
+        let options = [1; 40];
+        let mut ip = header(&options);
+        ip.header_checksum = ip.calc_checksum();
 
-        !sum as u16
+        let mut packet = ip.serialize()[..ip.size()].to_vec();
+        packet.extend([1, 2, 3, 0, 0]);
+
+        let (parsed, payload) = IPv4Header::new(&packet).unwrap();
+        assert_eq!(parsed, ip);
+        assert_eq!(payload, [1, 2, 3]);
+        assert_eq!(parsed.calc_checksum(), ip.header_checksum);
     }
 
-    pub fn size(&self) -> usize {
-        self.ihl as usize * 4
+    #[test]
+    fn rejects_malformed() {
+        let mut packet = header(&[]).serialize()[..20].to_vec();
+        packet.extend([0; 3]);
+
+        let parse = |packet: &[u8]| match IPv4Header::new(packet) {
+            Err(crate::error::Error::Malformed(e)) => Some(e),
+            _ => None,
+        };
+
+        assert_eq!(parse(&packet[..19]), Some(ParseError::Ipv4TooShort(19)));
+        assert_eq!(
+            parse(&packet[..22]),
+            Some(ParseError::Ipv4BadTotalLength(23))
+        );
+
+        let mut bad = packet.clone();
+        bad[0] = 0x65;
+        assert_eq!(parse(&bad), Some(ParseError::Ipv4BadVersion(6)));
+        bad[0] = 0x44;
+        assert_eq!(parse(&bad), Some(ParseError::Ipv4BadIhl(4)));
+        bad[0] = 0x4f;
+        assert_eq!(parse(&bad), Some(ParseError::Ipv4TruncatedOptions(15)));
+    }
+
+    #[test]
+    fn options_round_trip() {
+        let route = [10, 0, 0, 1, 10, 0, 0, 2];
+        let stamps = [10, 0, 0, 1, 0, 0, 0, 42];
+        let options = [
+            Ipv4Option::RecordRoute(8, &route),
+            Ipv4Option::Nop,
+            Ipv4Option::Timestamp(13, 1, 1, &stamps),
+            Ipv4Option::StreamId(0x1234),
+            Ipv4Option::Security(0xf135, 0, 0, [0; 3]),
+        ];
+        let mut data = options
+            .iter()
+            .flat_map(Ipv4Option::serialize)
+            .collect::<Vec<_>>();
+        data.resize(40, 0);
+
+        let ip = header(&data);
+        let parsed = ip.parse_options().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(parsed, options);
+        assert!(ip.check_options().is_ok());
+        assert!(!parsed[0].is_copied());
+        assert!(parsed[4].is_copied());
+    }
+
+    #[test]
+    fn malformed_options() {
+        let options = |data| Ipv4Options { data }.collect::<Vec<_>>();
+
+        assert!(options(&[0, 7, 3, 4]).is_empty());
+        // a route that is not made of addresses and a pointer before the data
+        assert!(matches!(options(&[7, 5, 4, 0, 0])[..], [Err(_)]));
+        assert!(matches!(options(&[7, 7, 3, 0, 0, 0, 0])[..], [Err(_)]));
+        // address and timestamp pairs that are cut short, and an unknown flag
+        assert!(matches!(options(&[68, 8, 5, 1, 0, 0, 0, 0])[..], [Err(_)]));
+        assert!(matches!(options(&[68, 8, 5, 2, 0, 0, 0, 0])[..], [Err(_)]));
+        assert!(matches!(options(&[136, 3, 0])[..], [Err(_)]));
+        assert!(matches!(options(&[30, 1])[..], [Err(_)]));
+        assert!(matches!(
+            options(&[30, 3, 1, 1])[..],
+            [Ok(Ipv4Option::Unknown(30, &[1])), Ok(Ipv4Option::Nop)]
+        ));
+
+        let ip = header(&[7, 5, 4, 0]);
+        assert!(ip.check_options().is_err());
+    }
+
+    #[test]
+    fn padding_after_the_end_of_the_options_is_skipped() {
+        let route = [10, 0, 0, 1];
+        let mut data = Ipv4Option::RecordRoute(4, &route).serialize();
+        data.push(Ipv4Option::END);
+        // neither the zeros nor a source route after the end count
+        data.extend([0, 0, Ipv4Option::STRICT_SOURCE_ROUTE, 7, 4, 10, 0, 0, 1]);
+        data.resize(24, 0);
+
+        let ip = header(&data);
+        let parsed = ip.parse_options().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(parsed, [Ipv4Option::RecordRoute(4, &route)]);
+        assert!(ip.check_options().is_ok());
+    }
+
+    #[test]
+    fn source_routes_are_rejected() {
+        let check = |options: &[u8]| match header(options).check_options() {
+            Err(crate::error::Error::Malformed(e)) => Some(e),
+            _ => None,
+        };
+
+        // even once the route is complete and this host is the destination
+        assert_eq!(
+            check(&[1, 131, 7, 8, 10, 0, 0, 1]),
+            Some(ParseError::Ipv4SourceRoute)
+        );
+        assert_eq!(
+            check(&[1, 137, 7, 4, 10, 0, 0, 1]),
+            Some(ParseError::Ipv4SourceRoute)
+        );
+        assert_eq!(check(&[1, 7, 7, 4, 0, 0, 0, 0]), None);
     }
 }
@@ -0,0 +1,2674 @@
+use std::{
+    borrow::Cow,
+    cmp::min,
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    os::fd::AsRawFd,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, LockResult, Mutex, MutexGuard, OnceLock,
+    },
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+use error::{Error, ParseError, Result};
+use listener::Listener;
+use log::{debug, error, trace};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+use rand::Rng;
+
+pub mod error;
+
+pub mod device;
+use device::{NetDevice, Offloads, VnetHeader};
+
+pub mod impairment;
+
+pub mod clock;
+use clock::{Clock, SystemClock};
+
+mod stepper;
+pub use stepper::Stepper;
+
+mod utils;
+use utils::{wrapping_between, ConnectionId};
+
+pub mod ipv4;
+use ipv4::IPv4Header;
+
+pub mod fragment;
+use fragment::Reassembler;
+
+pub mod tcp;
+use tcp::{TcpFlag, TcpHeader, TcpOption};
+
+pub mod listener;
+
+pub mod poll;
+
+mod async_io;
+
+mod wakeup;
+use wakeup::Wakeup;
+
+mod events;
+use events::Events;
+
+mod timer;
+use timer::{TimerId, TimerWheel};
+
+mod pool;
+use pool::{Outgoing, PacketPool};
+
+pub mod net;
+
+// drives Manager without a device or a processing thread, see fuzz/
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+
+#[derive(Clone, Debug, PartialEq)]
+enum State {
+    Closed,
+    Listen,
+    SynSent,
+    SynRecvd,
+    Estab,
+    // the peer has sent its FIN, ours waits for close() and the send queue
+    CloseWait,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    LastAck,
+}
+
+// 2*MSL, the same value linux uses
+const TIME_WAIT: Duration = Duration::from_secs(60);
+
+// how long a segment waits for its ACK before it is sent again, there is no
+// RTT estimate yet. The timeout doubles with every retransmission of the same
+// segment, https://datatracker.ietf.org/doc/html/rfc6298#section-5
+const RETRANSMIT: Duration = Duration::from_millis(200);
+const MAX_RETRANSMIT: Duration = Duration::from_secs(60);
+
+// retransmissions of a segment before the connection is given up, almost 4
+// minutes with the timeouts above
+const RETRIES: u32 = 10;
+
+// what a peer that sends no MSS option accepts,
+// https://datatracker.ietf.org/doc/html/rfc9293#section-3.7.1
+const DEFAULT_MSS: usize = 536;
+
+// every IPv4 link carries packets this large, and no packet is larger
+const MTU_RANGE: std::ops::RangeInclusive<usize> = 68..=65535;
+
+// how much unacknowledged data write() accepts before blocking
+const SEND_BUFFER: usize = 64 * 1024;
+
+// how much received data is queued for read(), the free space is the window
+// advertised to the peer, so it is as large as it gets without window scaling
+const RECV_BUFFER: usize = u16::MAX as usize;
+
+// https://datatracker.ietf.org/doc/html/rfc6335#section-6
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+// locked by the application threads using the connection and by the
+// processing thread, but never while the thread holds another connection.
+// The calls blocked on the connection wait on its own events, so a frame
+// only wakes the threads using the connection it changed
+#[derive(Debug)]
+struct Shared {
+    conn: Mutex<Connection>,
+    events: Events,
+}
+
+type SharedConnection = Arc<Shared>;
+
+impl Shared {
+    fn new(conn: Connection) -> SharedConnection {
+        Arc::new(Shared {
+            conn: Mutex::new(conn),
+            events: Events::default(),
+        })
+    }
+
+    fn lock(&self) -> LockResult<MutexGuard<'_, Connection>> {
+        self.conn.lock()
+    }
+}
+
+// where a Connection is kept in the Manager
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum ConnectionKey {
+    Conn(ConnectionId),
+    Listen((u32, u16)),
+}
+
+// per connection settings, shared by every handle to it
+#[derive(Debug, Default)]
+struct SocketOptions {
+    nonblocking: bool,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    linger: Option<Duration>,
+}
+
+// the last segment on_tick sent, see Connection::due
+#[derive(Debug)]
+struct InFlight {
+    // a segment for another state or sequence number is a new one
+    state: State,
+    seq: u32,
+    // when it is sent again
+    at: Instant,
+    timeout: Duration,
+    retries: u32,
+}
+
+pub struct Connection {
+    state: State,
+    server_port: u16,
+    client_port: u16,
+    server_ip: u32,
+    client_ip: u32,
+    recv_seq: u32,
+    send_seq: u32,
+    send_window: u16,
+    // the largest segment the peer accepts, from its SYN
+    peer_mss: usize,
+    send_queue: VecDeque<u8>,
+    // at most RECV_BUFFER bytes
+    recv_queue: VecDeque<u8>,
+    // read() has made room the peer does not know about yet, on_tick sends
+    // it an ACK
+    window_update: bool,
+    closing: bool,
+    read_closed: bool,
+    orphaned: bool,
+    reset: bool,
+    // reset because the peer stopped acknowledging, calls fail with TimedOut
+    // instead of ConnectionReset
+    timed_out: bool,
+    time_wait_until: Option<Instant>,
+    in_flight: Option<InFlight>,
+    // armed in Manager::timers for deadline()
+    timer: Option<(TimerId, Instant)>,
+    // live ConnectionHandles, the last one to be dropped closes the connection
+    handles: usize,
+    // removed from the Manager, e.g. by abort()
+    detached: bool,
+    // the tasks waiting to read from the connection (or to accept from the
+    // listener) and to write to or close it, see async_io
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    options: SocketOptions,
+}
+
+// the queues are only counted, formatting the data in them would take far
+// longer than handling a frame
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("id", &self.id())
+            .field("state", &self.state)
+            .field("send_seq", &self.send_seq)
+            .field("recv_seq", &self.recv_seq)
+            .field("send_window", &self.send_window)
+            .field("send_queue", &self.send_queue.len())
+            .field("recv_queue", &self.recv_queue.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Connection {
+    fn new(ip: u32, port: u16) -> Connection {
+        Connection {
+            state: State::Listen,
+            server_port: port,
+            client_port: 0,
+            server_ip: ip,
+            client_ip: 0,
+            recv_seq: 0,
+            send_seq: rand::thread_rng().gen(),
+            send_window: 0,
+            peer_mss: DEFAULT_MSS,
+            send_queue: VecDeque::new(),
+            recv_queue: VecDeque::new(),
+            window_update: false,
+            closing: false,
+            read_closed: false,
+            orphaned: false,
+            reset: false,
+            timed_out: false,
+            time_wait_until: None,
+            in_flight: None,
+            timer: None,
+            handles: 0,
+            detached: false,
+            read_waker: None,
+            write_waker: None,
+            options: SocketOptions::default(),
+        }
+    }
+
+    fn id(&self) -> ConnectionId {
+        ConnectionId {
+            ip_src: self.client_ip,
+            ip_dst: self.server_ip,
+            port_src: self.client_port,
+            port_dst: self.server_port,
+        }
+    }
+
+    fn on_message(
+        &mut self,
+        data: &[u8],
+        ip: &IPv4Header,
+        tcp: &TcpHeader,
+        now: Instant,
+        out: &mut Outgoing,
+    ) -> Result<()> {
+        if ip.dest_ip != self.server_ip || tcp.dest_port != self.server_port {
+            trace!("{:?}: invalid dest ip or port", self.id());
+            out.segment(
+                &self.id(),
+                TcpFlag::Rst | TcpFlag::Ack,
+                tcp.sequence_number.wrapping_add(1),
+                tcp.sequence_number
+                    .wrapping_add(1)
+                    .wrapping_add(data.len() as u32),
+                0,
+                &[],
+            );
+
+            return Err(Error::InvalidInput("invalid dest ip or port"));
+        }
+
+        match self.state {
+            State::Listen if tcp.get_flag(TcpFlag::Syn) => {
+                trace!("{:?}: got SYN", self.id());
+
+                self.state = State::SynRecvd;
+                self.recv_seq = tcp.sequence_number.wrapping_add(1);
+                self.client_ip = ip.source_ip;
+                self.client_port = tcp.source_port;
+                self.peer_mss = peer_mss(tcp);
+
+                self.send_syn_ack(out);
+                self.sent(now);
+            }
+            State::SynRecvd if tcp.get_flag(TcpFlag::Ack) => {
+                if tcp.ack_number != self.send_seq.wrapping_add(1) || tcp.get_flag(TcpFlag::Syn) {
+                    trace!("{:?}: got invalid ack, sending RST", self.id());
+
+                    self.state = State::Closed;
+                    self.reset = true;
+                    out.segment(
+                        &self.id(),
+                        TcpFlag::Rst as u8,
+                        tcp.ack_number,
+                        tcp.sequence_number.wrapping_add(data.len() as u32),
+                        0,
+                        &[],
+                    );
+
+                    return Ok(());
+                }
+
+                debug!("{:?}: got ACK of SYN, connection established", self.id());
+
+                self.send_seq = self.send_seq.wrapping_add(1);
+                self.send_window = tcp.window_size;
+                self.state = State::Estab;
+            }
+            _ if tcp.get_flag(TcpFlag::Rst) => {
+                debug!("{:?}: got RST, connection closed", self.id());
+                self.state = State::Closed;
+                self.reset = true;
+            }
+            State::SynSent if tcp.get_flag(TcpFlag::Syn) && tcp.get_flag(TcpFlag::Ack) => {
+                if tcp.ack_number != self.send_seq.wrapping_add(1) {
+                    trace!("{:?}: got SYN-ACK with invalid ack, sending RST", self.id());
+                    out.segment(&self.id(), TcpFlag::Rst as u8, tcp.ack_number, 0, 0, &[]);
+
+                    return Ok(());
+                }
+
+                debug!("{:?}: got SYN-ACK, connection established", self.id());
+
+                self.recv_seq = tcp.sequence_number.wrapping_add(1);
+                self.send_seq = self.send_seq.wrapping_add(1);
+                self.send_window = tcp.window_size;
+                self.peer_mss = peer_mss(tcp);
+                self.state = State::Estab;
+                self.send_ack(out);
+            }
+            State::FinWait1 | State::FinWait2 | State::Closing => {
+                self.on_closing_message(data, tcp, now, out);
+            }
+            State::TimeWait if tcp.get_flag(TcpFlag::Fin) => {
+                trace!("{:?}: got a retransmitted FIN in TIME-WAIT", self.id());
+                self.send_ack(out);
+            }
+            State::LastAck
+                if tcp.get_flag(TcpFlag::Ack)
+                    && tcp.ack_number == self.send_seq.wrapping_add(1) =>
+            {
+                debug!("{:?}: got ACK of FIN, connection closed", self.id());
+                self.send_seq = self.send_seq.wrapping_add(1);
+                self.state = State::Closed;
+            }
+            State::Estab | State::CloseWait => {
+                if !tcp.get_flag(TcpFlag::Ack) {
+                    trace!("{:?}: ACK not set", self.id());
+                    return Ok(());
+                }
+
+                if tcp.sequence_number != self.recv_seq
+                    || !wrapping_between(
+                        self.send_seq,
+                        tcp.ack_number,
+                        self.send_seq.wrapping_add(self.send_window as u32),
+                    )
+                {
+                    trace!("{:?}: sending an empty packet", self.id());
+                    self.send_ack(out);
+
+                    return Ok(());
+                }
+
+                self.on_window(tcp.window_size);
+
+                // the window check above makes the difference small and positive,
+                // but the peer may still ack more than was ever queued
+                let amount = tcp.ack_number.wrapping_sub(self.send_seq) as usize;
+                if amount > 0 {
+                    self.send_seq = tcp.ack_number;
+                    self.send_queue.drain(..amount.min(self.send_queue.len()));
+                }
+
+                // nothing follows the peer's FIN
+                if self.state == State::CloseWait {
+                    return Ok(());
+                }
+
+                // data that does not fit is dropped, the ACK tells the peer
+                // how much was taken. The FIN may carry the last of the data,
+                // after data that did not fit it is dropped with it
+                let received = self.receive(data);
+                if tcp.get_flag(TcpFlag::Fin) && received == data.len() {
+                    trace!("{:?}: got FIN", self.id());
+                    self.recv_seq = self.recv_seq.wrapping_add(1);
+                    self.state = State::CloseWait;
+                    // data in flight keeps its retransmission timer
+                    if let Some(sent) = &mut self.in_flight {
+                        sent.state = State::CloseWait;
+                    }
+                    self.send_ack(out);
+                } else if !data.is_empty() {
+                    self.send_ack(out);
+                }
+            }
+            State::Closed if !tcp.get_flag(TcpFlag::Rst) => {
+                trace!(
+                    "{:?}: got a packet in a closed connection, sending RST",
+                    self.id()
+                );
+
+                out.segment(
+                    &self.id(),
+                    TcpFlag::Rst as u8,
+                    if tcp.get_flag(TcpFlag::Ack) {
+                        tcp.ack_number
+                    } else {
+                        0
+                    },
+                    tcp.sequence_number.wrapping_add(data.len() as u32),
+                    0,
+                    &[],
+                );
+            }
+            _ => {
+                trace!("{:?}: ignoring a segment in {:?}", self.id(), self.state);
+            }
+        }
+
+        Ok(())
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc9293#section-3.10.7.4
+    fn on_closing_message(
+        &mut self,
+        data: &[u8],
+        tcp: &TcpHeader,
+        now: Instant,
+        out: &mut Outgoing,
+    ) {
+        if tcp.sequence_number != self.recv_seq {
+            trace!(
+                "{:?}: out of order segment while closing, sending an empty packet",
+                self.id()
+            );
+            self.send_ack(out);
+            return;
+        }
+
+        if tcp.get_flag(TcpFlag::Ack)
+            && tcp.ack_number == self.send_seq.wrapping_add(1)
+            && matches!(self.state, State::FinWait1 | State::Closing)
+        {
+            trace!("{:?}: got ACK of FIN", self.id());
+
+            self.send_seq = self.send_seq.wrapping_add(1);
+            self.state = match self.state {
+                State::FinWait1 => State::FinWait2,
+                _ => self.enter_time_wait(now),
+            };
+        }
+
+        // a FIN after data that did not fit is dropped with it
+        let received = self.receive(data);
+
+        if tcp.get_flag(TcpFlag::Fin) && received == data.len() {
+            trace!("{:?}: got FIN while closing", self.id());
+
+            self.recv_seq = self.recv_seq.wrapping_add(1);
+            self.state = match self.state {
+                State::FinWait1 => State::Closing,
+                _ => self.enter_time_wait(now),
+            };
+            self.send_ack(out);
+        } else if !data.is_empty() {
+            self.send_ack(out);
+        }
+    }
+
+    // the window the peer advertised. While it is closed on_tick probes it
+    // with single bytes, once it opens the queued data goes out right away
+    // instead of at the next probe
+    fn on_window(&mut self, window: u16) {
+        if self.send_window == 0 && window > 0 {
+            self.in_flight = None;
+        } else if window == 0 {
+            // the peer answers the probes, it only has no room
+            if let Some(sent) = &mut self.in_flight {
+                sent.retries = 0;
+            }
+        }
+        self.send_window = window;
+    }
+
+    fn enter_time_wait(&mut self, now: Instant) -> State {
+        self.time_wait_until = Some(now + TIME_WAIT);
+        State::TimeWait
+    }
+
+    // whether on_tick has to send the segment for the current state: it has
+    // not been sent yet or has gone unacknowledged for its timeout, which is
+    // then doubled
+    fn due(&mut self, now: Instant) -> bool {
+        match &mut self.in_flight {
+            Some(sent) if sent.state == self.state && sent.seq == self.send_seq => {
+                if sent.at > now {
+                    return false;
+                }
+
+                sent.retries += 1;
+                sent.timeout = (sent.timeout * 2).min(MAX_RETRANSMIT);
+                sent.at = now + sent.timeout;
+            }
+            _ => self.sent(now),
+        }
+
+        true
+    }
+
+    // the segment for the current state has just been sent for the first time
+    fn sent(&mut self, now: Instant) {
+        self.in_flight = Some(InFlight {
+            state: self.state.clone(),
+            seq: self.send_seq,
+            at: now + RETRANSMIT,
+            timeout: RETRANSMIT,
+            retries: 0,
+        });
+    }
+
+    // gives up on a peer that has acknowledged nothing for RETRIES
+    // retransmissions. Like BSD's tcp_drop the peer gets a RST, in case it
+    // only can't be heard
+    fn time_out(&mut self, out: &mut Outgoing) {
+        debug!(
+            "{:?}: retransmission timeout, resetting the connection",
+            self.id()
+        );
+        self.abort(out);
+        self.state = State::Closed;
+        self.reset = true;
+        self.timed_out = true;
+    }
+
+    // what calls on a reset connection fail with
+    fn reset_error(&self) -> Error {
+        match self.timed_out {
+            true => Error::TimedOut,
+            false => Error::ConnectionReset,
+        }
+    }
+
+    fn wake_tasks(&mut self) {
+        for waker in [self.read_waker.take(), self.write_waker.take()]
+            .into_iter()
+            .flatten()
+        {
+            waker.wake();
+        }
+    }
+
+    // timers, retransmissions and queued data
+    fn on_tick(&mut self, now: Instant, out: &mut Outgoing) {
+        if self.state == State::TimeWait && self.time_wait_until.is_some_and(|t| t <= now) {
+            self.state = State::Closed;
+        }
+
+        if self.closing && self.send_queue.is_empty() {
+            match self.state {
+                State::Estab => self.state = State::FinWait1,
+                State::CloseWait => self.state = State::LastAck,
+                _ => {}
+            }
+        }
+
+        // the peer may be waiting for the room read() has made
+        if std::mem::take(&mut self.window_update)
+            && matches!(self.state, State::Estab | State::FinWait1 | State::FinWait2)
+        {
+            self.send_ack(out);
+        }
+
+        let sending = match self.state {
+            State::SynSent | State::SynRecvd => true,
+            State::FinWait1 | State::Closing | State::LastAck => true,
+            State::Estab | State::CloseWait => !self.send_queue.is_empty(),
+            _ => false,
+        };
+        if !sending || !self.due(now) {
+            return;
+        }
+        if self
+            .in_flight
+            .as_ref()
+            .is_some_and(|sent| sent.retries > RETRIES)
+        {
+            self.time_out(out);
+            return;
+        }
+
+        match self.state {
+            State::SynSent => self.send_syn(out),
+            // a lost SYN-ACK is only recovered by sending it again
+            State::SynRecvd => self.send_syn_ack(out),
+            State::Estab | State::CloseWait => {
+                // a closed window is probed with one byte
+                let window = self.send_window.max(1);
+                let size = self.send_queue.len().min(window.into());
+                let size = size.min(out.max_segment(self.peer_mss));
+                // the queue's ring buffer may wrap around inside the segment
+                let (front, back) = self.send_queue.as_slices();
+                let split = front.len().min(size);
+                let text = [&front[..split], &back[..size - split]];
+
+                out.data(
+                    &self.id(),
+                    self.send_seq,
+                    self.recv_seq,
+                    self.window(),
+                    &text,
+                    self.peer_mss,
+                );
+            }
+            _ => self.send_fin(out),
+        }
+    }
+
+    // moves the connection's timer in the wheel to deadline()
+    fn set_timer(&mut self, key: ConnectionKey, timers: &mut TimerWheel<ConnectionKey>) {
+        let deadline = self.deadline();
+        if self.timer.map(|(_, at)| at) == deadline {
+            return;
+        }
+
+        if let Some((timer, _)) = self.timer.take() {
+            timers.cancel(timer);
+        }
+        if let Some(at) = deadline {
+            self.timer = Some((timers.schedule(key, at), at));
+        }
+    }
+
+    // when on_tick has something to do for this connection
+    fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::TimeWait => self.time_wait_until,
+            State::SynSent
+            | State::SynRecvd
+            | State::FinWait1
+            | State::Closing
+            | State::LastAck => self.in_flight.as_ref().map(|sent| sent.at),
+            State::Estab | State::CloseWait if !self.send_queue.is_empty() => {
+                self.in_flight.as_ref().map(|sent| sent.at)
+            }
+            _ => None,
+        }
+    }
+
+    fn send_ack(&self, out: &mut Outgoing) {
+        out.segment(
+            &self.id(),
+            TcpFlag::Ack as u8,
+            self.next_seq(),
+            self.recv_seq,
+            self.window(),
+            &[],
+        );
+    }
+
+    // what follows everything sent so far: a FIN that has not been
+    // acknowledged yet takes up a sequence number after send_seq
+    fn next_seq(&self) -> u32 {
+        match self.state {
+            State::FinWait1 | State::Closing | State::LastAck => self.send_seq.wrapping_add(1),
+            _ => self.send_seq,
+        }
+    }
+
+    fn send_syn(&self, out: &mut Outgoing) {
+        out.syn(
+            &self.id(),
+            TcpFlag::Syn as u8,
+            self.send_seq,
+            0,
+            self.window(),
+        );
+    }
+
+    fn send_syn_ack(&self, out: &mut Outgoing) {
+        out.syn(
+            &self.id(),
+            TcpFlag::Syn | TcpFlag::Ack,
+            self.send_seq,
+            self.recv_seq,
+            self.window(),
+        );
+    }
+
+    fn send_fin(&self, out: &mut Outgoing) {
+        out.segment(
+            &self.id(),
+            TcpFlag::Fin | TcpFlag::Ack,
+            self.send_seq,
+            self.recv_seq,
+            self.window(),
+            &[],
+        );
+    }
+
+    // the free space of the receive buffer, advertised in every segment
+    fn window(&self) -> u16 {
+        (RECV_BUFFER - self.recv_queue.len()) as u16
+    }
+
+    // queues as much of `data`, which starts at recv_seq, as the receive
+    // buffer has room for and returns how much that was. The peer has to
+    // send the rest again. After shutdown(Read) the data is only acknowledged
+    fn receive(&mut self, data: &[u8]) -> usize {
+        let len = data.len().min(self.window().into());
+        if !self.read_closed {
+            self.recv_queue.extend(&data[..len]);
+        }
+        self.recv_seq = self.recv_seq.wrapping_add(len as u32);
+        len
+    }
+
+    // read() or shutdown() took data out of the receive buffer, which had
+    // been more than half full. Once it is no longer the peer is told about
+    // the space, it may be waiting for it
+    fn window_opened(&mut self, queued: usize) {
+        let half = RECV_BUFFER / 2;
+        if queued > half && self.recv_queue.len() <= half {
+            self.window_update = true;
+        }
+    }
+
+    // the FIN has been sent and acknowledged, so everything before it was delivered
+    fn delivered(&self) -> bool {
+        match self.state {
+            State::FinWait2 | State::TimeWait => true,
+            State::Closed => !self.reset && self.send_queue.is_empty(),
+            _ => false,
+        }
+    }
+
+    // the peer has sent its FIN, so nothing more will arrive
+    fn at_eof(&self) -> bool {
+        match self.state {
+            State::CloseWait | State::Closing | State::TimeWait | State::LastAck => true,
+            State::Closed => !self.reset,
+            _ => false,
+        }
+    }
+
+    // read() would not block
+    fn readable(&self) -> bool {
+        !self.recv_queue.is_empty() || self.read_closed || self.reset || self.at_eof()
+    }
+
+    // write() would not block
+    fn writable(&self) -> bool {
+        self.send_queue.len() < SEND_BUFFER
+            || self.reset
+            || self.closing
+            || !matches!(
+                self.state,
+                State::SynRecvd | State::Estab | State::CloseWait
+            )
+    }
+
+    // WouldBlock means the caller has to wait for the processing thread
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.reset {
+            return Err(self.reset_error());
+        }
+        // the peer's FIN only closes its side, it can still receive
+        if self.closing
+            || !matches!(
+                self.state,
+                State::SynRecvd | State::Estab | State::CloseWait
+            )
+        {
+            return Err(Error::BrokenPipe);
+        }
+
+        let len = min(buf.len(), SEND_BUFFER.saturating_sub(self.send_queue.len()));
+        if len == 0 && !buf.is_empty() {
+            return Err(Error::WouldBlock);
+        }
+
+        self.send_queue.extend(&buf[..len]);
+        Ok(len)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.read_closed {
+            return Ok(0);
+        }
+        if !self.recv_queue.is_empty() || buf.is_empty() {
+            let queued = self.recv_queue.len();
+            let read = self.recv_queue.read(buf)?;
+            self.window_opened(queued);
+            return Ok(read);
+        }
+        if self.reset {
+            return Err(self.reset_error());
+        }
+        if self.at_eof() {
+            return Ok(0);
+        }
+
+        Err(Error::WouldBlock)
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc9293#section-3.10.5
+    fn abort(&self, out: &mut Outgoing) {
+        let next_seq = match self.state {
+            State::Closed | State::Listen | State::SynSent => return,
+            State::SynRecvd => self.send_seq.wrapping_add(1),
+            State::Estab | State::CloseWait => {
+                let in_flight = self.send_queue.len().min(self.send_window.into());
+                let in_flight = in_flight.min(out.max_segment(self.peer_mss));
+                self.send_seq.wrapping_add(in_flight as u32)
+            }
+            State::FinWait1 | State::Closing | State::LastAck => self.send_seq.wrapping_add(1),
+            State::FinWait2 | State::TimeWait => self.send_seq,
+        };
+
+        debug!("{:?}: aborting connection, sending RST", self.id());
+
+        out.segment(
+            &self.id(),
+            TcpFlag::Rst | TcpFlag::Ack,
+            next_seq,
+            self.recv_seq,
+            0,
+            &[],
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionHandle {
+    mgr: ConnectionManager,
+    id: ConnectionId,
+    conn: SharedConnection,
+}
+
+impl ConnectionHandle {
+    // only this connection is locked, the processing thread can keep working
+    // on the others
+    fn lock(&self) -> Result<MutexGuard<'_, Connection>> {
+        self.mgr.check_running()?;
+        let conn = self.conn.lock().unwrap();
+        if conn.detached {
+            return Err(Error::NotConnected);
+        }
+
+        Ok(conn)
+    }
+
+    fn with_conn<T>(&self, f: impl FnOnce(&mut Connection) -> T) -> Result<T> {
+        Ok(f(&mut *self.lock()?))
+    }
+
+    // retries `op` every time the processing thread reports progress
+    fn blocking<T>(
+        &self,
+        timeout: fn(&SocketOptions) -> Option<Duration>,
+        mut op: impl FnMut(&mut Connection) -> Result<T>,
+    ) -> Result<T> {
+        let mut deadline = None;
+
+        loop {
+            let seen = self.conn.events.generation();
+            let mut conn = self.lock()?;
+
+            let nonblocking = conn.options.nonblocking;
+            match op(&mut conn) {
+                Err(Error::WouldBlock) if !nonblocking => {}
+                result => return result,
+            }
+
+            let deadline =
+                *deadline.get_or_insert_with(|| timeout(&conn.options).map(|t| Instant::now() + t));
+            drop(conn);
+
+            if !self.conn.events.wait(seen, deadline) {
+                return Err(Error::TimedOut);
+            }
+        }
+    }
+
+    // lets the processing thread send what the application just queued
+    fn changed(&self) {
+        self.mgr.shard(&self.id).mark_ready(&self.id);
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.id.ip_dst.into(), self.id.port_dst)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.id.ip_src.into(), self.id.port_src)
+    }
+
+    // another handle to the same connection, sharing its options like a dup()ed socket
+    pub fn try_clone(&self) -> Result<ConnectionHandle> {
+        self.with_conn(|conn| conn.handles += 1)?;
+
+        Ok(ConnectionHandle {
+            mgr: self.mgr.clone(),
+            id: self.id.clone(),
+            conn: self.conn.clone(),
+        })
+    }
+
+    // like shutdown(2), Write sends FIN once the send queue is drained and
+    // Read makes every following read return Ok(0)
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.detached {
+            return Err(Error::NotConnected);
+        }
+
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            conn.read_closed = true;
+            let queued = conn.recv_queue.len();
+            conn.recv_queue.clear();
+            conn.window_opened(queued);
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            conn.closing = true;
+        }
+
+        conn.wake_tasks();
+        drop(conn);
+        self.conn.events.notify();
+        self.changed();
+        Ok(())
+    }
+
+    // read and write return WouldBlock instead of waiting
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.with_conn(|conn| conn.options.nonblocking = nonblocking)
+    }
+
+    // None (the default) blocks forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.with_conn(|conn| conn.options.read_timeout = timeout)
+    }
+
+    pub fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.with_conn(|conn| conn.options.read_timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.with_conn(|conn| conn.options.write_timeout = timeout)
+    }
+
+    pub fn write_timeout(&self) -> Result<Option<Duration>> {
+        self.with_conn(|conn| conn.options.write_timeout)
+    }
+
+    // abortive close, like SO_LINGER with a zero timeout: both queues are
+    // discarded and the peer gets a RST instead of a FIN
+    pub fn abort(self) {
+        let shard = self.mgr.shard(&self.id);
+        let mut mgr = shard.mgr.lock().unwrap();
+        let Some(conn) = mgr.remove(&ConnectionKey::Conn(self.id.clone())) else {
+            return;
+        };
+
+        let mut conn = conn.lock().unwrap();
+        conn.abort(&mut mgr.outgoing);
+        conn.wake_tasks();
+        drop(conn);
+        drop(mgr);
+        shard.wake();
+    }
+
+    // same meaning as SO_LINGER: None closes in the background, Some(timeout)
+    // makes close() wait for the peer to acknowledge everything, and a zero
+    // timeout turns close() into abort()
+    pub fn set_linger(&self, linger: Option<Duration>) -> Result<()> {
+        self.with_conn(|conn| conn.options.linger = linger)
+    }
+
+    pub fn linger(&self) -> Result<Option<Duration>> {
+        self.with_conn(|conn| conn.options.linger)
+    }
+
+    // sends FIN once the send queue is drained, returns whether all the data
+    // was acknowledged by the peer before returning. Like close(2) on a dup()ed
+    // socket, only closing the last handle closes the connection, before that
+    // it only tells whether the send queue is empty
+    pub fn close(self) -> bool {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.detached {
+            return false;
+        }
+        // dropping self takes the handle away
+        if conn.handles > 1 {
+            return conn.send_queue.is_empty();
+        }
+
+        if conn.options.linger == Some(Duration::ZERO) {
+            let delivered = conn.send_queue.is_empty();
+            drop(conn);
+            self.abort();
+            return delivered;
+        }
+
+        conn.closing = true;
+        let linger = conn.options.linger;
+        let queued = !conn.send_queue.is_empty();
+        drop(conn);
+        self.changed();
+
+        let Some(linger) = linger else {
+            return !queued;
+        };
+
+        // if the timeout expires the connection keeps closing in the
+        // background, like linux does
+        let deadline = Some(Instant::now() + linger);
+        loop {
+            let seen = self.conn.events.generation();
+            let conn = self.conn.lock().unwrap();
+            if conn.detached {
+                return false;
+            }
+
+            let delivered = conn.delivered();
+            if delivered || conn.state == State::Closed {
+                return delivered;
+            }
+            drop(conn);
+
+            if !self.conn.events.wait(seen, deadline) {
+                return false;
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        let Ok(mut conn) = self.conn.lock() else {
+            return;
+        };
+        if conn.detached {
+            return;
+        }
+
+        conn.handles -= 1;
+        if conn.handles == 0 {
+            conn.closing = true;
+            conn.orphaned = true;
+            drop(conn);
+            self.changed();
+        }
+    }
+}
+
+// blocks until data arrives, Ok(0) means the peer has closed its side
+impl Read for &ConnectionHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (read, window_update) = self.blocking(
+            |opts| opts.read_timeout,
+            |conn| conn.read(buf).map(|read| (read, conn.window_update)),
+        )?;
+        if window_update {
+            self.changed();
+        }
+        Ok(read)
+    }
+}
+
+// blocks while the send buffer is full
+impl Write for &ConnectionHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.blocking(|opts| opts.write_timeout, |conn| conn.write(buf))?;
+        if written > 0 {
+            self.changed();
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for ConnectionHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for ConnectionHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+// the table of connections of one shard, only processing threads and calls
+// that add or remove connections lock it. Each connection has its own lock,
+// which is all that reads and writes take
+#[derive(Debug)]
+struct Manager {
+    conns: HashMap<ConnectionId, SharedConnection>,
+    listen: HashMap<(u32, u16), SharedConnection>,
+    outgoing: Outgoing,
+    // what the device does for the stack, and so the format of its frames
+    offloads: Offloads,
+    // drives the protocol timers
+    clock: Arc<dyn Clock>,
+    // one timer per connection, at its Connection::deadline()
+    timers: TimerWheel<ConnectionKey>,
+    // what the next on_tick has to look at: connections that received a
+    // packet or were changed by the application since the last one. The
+    // sending side is shared with the application threads
+    ready: Receiver<ConnectionKey>,
+    ready_tx: Sender<ConnectionKey>,
+}
+
+// one Manager and the processing thread that ticks it. A connection lives in
+// the shard its 4-tuple hashes to, listeners live in the first one
+#[derive(Debug)]
+struct Shard {
+    mgr: Mutex<Manager>,
+    ready: Sender<ConnectionKey>,
+    // signalled by application threads when there is something to send, None
+    // without a processing thread
+    wakeup: Option<Wakeup>,
+}
+
+impl Shard {
+    // makes the processing thread run on_tick, after the application has
+    // queued data or changed the state of a connection
+    fn wake(&self) {
+        if let Some(wakeup) = &self.wakeup {
+            wakeup.wake();
+        }
+    }
+
+    // like Manager::mark_ready, without locking the Manager
+    fn mark_ready(&self, id: &ConnectionId) {
+        let _ = self.ready.send(ConnectionKey::Conn(id.clone()));
+        self.wake();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionManager {
+    shards: Arc<[Shard]>,
+    // why a processing thread stopped, if one did
+    failed: Arc<OnceLock<Error>>,
+    // the frame buffers of all shards
+    pool: Arc<PacketPool>,
+    // of the device, segments and buffers are sized for it
+    mtu: usize,
+    // fragments from all queues, a datagram's fragments may arrive on
+    // different ones
+    reassembly: Arc<Mutex<Reassembler>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ConnectionManager {
+    // starts the processing thread on `device`, e.g. device::tun("tun0")
+    pub fn new(device: impl NetDevice) -> Result<ConnectionManager> {
+        ConnectionManager::with_clock(device, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(device: impl NetDevice, clock: Arc<dyn Clock>) -> Result<ConnectionManager> {
+        ConnectionManager::with_queues(vec![device], clock)
+    }
+
+    // one shard and one processing thread per queue, e.g. the queues of
+    // device::tun_queues. Frames may arrive on any queue and are handed to
+    // the shard of their connection
+    pub fn with_queues<D: NetDevice>(
+        queues: Vec<D>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<ConnectionManager> {
+        if queues.is_empty() {
+            return Err(Error::InvalidInput("at least one queue is needed"));
+        }
+
+        let wakeups = queues
+            .iter()
+            .map(|_| Wakeup::new().map(Some))
+            .collect::<io::Result<_>>()?;
+        let offloads = queues[0].offloads();
+        let mtu = queues[0].mtu();
+        let output = ConnectionManager::with_shards(clock, wakeups, offloads, mtu)?;
+
+        for (queue, device) in queues.into_iter().enumerate() {
+            let mgr_process = output.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = mgr_process.process_connections(queue, &device) {
+                    error!("processing thread {queue} stopped: {e}");
+                    let _ = mgr_process.failed.set(e);
+                    mgr_process.notify_all();
+                }
+            });
+        }
+
+        Ok(output)
+    }
+
+    // nothing happens until the returned Stepper is stepped, for tests that
+    // need to control the order of events
+    pub fn stepped<D: NetDevice>(
+        device: D,
+        clock: Arc<dyn Clock>,
+    ) -> Result<(ConnectionManager, Stepper<D>)> {
+        let mgr =
+            ConnectionManager::with_shards(clock, vec![None], device.offloads(), device.mtu())?;
+        Ok((mgr.clone(), Stepper::new(mgr, device)))
+    }
+
+    fn with_shards(
+        clock: Arc<dyn Clock>,
+        wakeups: Vec<Option<Wakeup>>,
+        offloads: Offloads,
+        mtu: usize,
+    ) -> Result<ConnectionManager> {
+        if !MTU_RANGE.contains(&mtu) {
+            return Err(Error::InvalidInput("MTU out of range"));
+        }
+
+        let pool = Arc::new(PacketPool::new(offloads.header_len() + mtu));
+        let shards = wakeups
+            .into_iter()
+            .map(|wakeup| {
+                let mgr = Manager::new(clock.clone(), pool.clone(), offloads, mtu);
+                Shard {
+                    ready: mgr.ready_tx.clone(),
+                    mgr: Mutex::new(mgr),
+                    wakeup,
+                }
+            })
+            .collect();
+
+        Ok(ConnectionManager {
+            shards,
+            failed: Arc::new(OnceLock::new()),
+            pool,
+            mtu,
+            reassembly: Arc::new(Mutex::new(Reassembler::new())),
+            clock,
+        })
+    }
+
+    // wakes every blocked call, e.g. to let them see that a processing
+    // thread has stopped
+    fn notify_all(&self) {
+        for shard in self.shards.iter() {
+            let mgr = shard.mgr.lock().unwrap();
+            for shared in mgr.conns.values().chain(mgr.listen.values()) {
+                shared.lock().unwrap().wake_tasks();
+                shared.events.notify();
+            }
+        }
+    }
+
+    // the largest IP packet the device carries, see NetDevice::mtu
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    // the same for both ends of a connection, like a symmetric RSS hash, so
+    // that the peer on the other side of a queue pair puts the connection on
+    // the same queue. tun does its part by delivering a flow on the queue it
+    // was last sent on
+    fn shard_index(&self, id: &ConnectionId) -> usize {
+        if self.shards.len() == 1 {
+            return 0;
+        }
+
+        let ends = [(id.ip_src, id.port_src), (id.ip_dst, id.port_dst)];
+        let mut hasher = DefaultHasher::new();
+        (ends[0].min(ends[1]), ends[0].max(ends[1])).hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, id: &ConnectionId) -> &Shard {
+        &self.shards[self.shard_index(id)]
+    }
+
+    fn listeners(&self) -> &Shard {
+        &self.shards[0]
+    }
+
+    // once a processing thread is gone nothing will ever make progress
+    fn check_running(&self) -> Result<()> {
+        match self.failed.get() {
+            Some(e) => Err(Error::Device(io::Error::new(e.kind(), e.to_string()))),
+            None => Ok(()),
+        }
+    }
+
+    // a port on local_ip that is neither listening nor used towards
+    // `remote` in any shard. The shards are locked one after the other, the
+    // caller checks again when it inserts the connection
+    fn ephemeral_port(&self, local_ip: u32, remote: Option<(u32, u16)>) -> Result<u16> {
+        ephemeral_port(|port| {
+            self.shards.iter().any(|shard| {
+                shard
+                    .mgr
+                    .lock()
+                    .unwrap()
+                    .port_in_use(local_ip, port, remote)
+            })
+        })
+    }
+
+    pub fn bind(&self, ip_str: &str, port: u16) -> Result<Listener> {
+        let ip: Ipv4Addr = ip_str
+            .parse()
+            .map_err(|_| Error::InvalidInput("invalid IPv4 address"))?;
+
+        self.listen(ip.into(), port)
+    }
+
+    // port 0 picks a free ephemeral port
+    fn listen(&self, ip: u32, port: u16) -> Result<Listener> {
+        let port = match port {
+            0 => self.ephemeral_port(ip, None)?,
+            port => port,
+        };
+
+        let mut mgr = self.listeners().mgr.lock().unwrap();
+        if mgr.listen.contains_key(&(ip, port)) {
+            return Err(Error::AddrInUse);
+        }
+
+        let conn = Shared::new(Connection::new(ip, port));
+        mgr.listen.insert((ip, port), conn.clone());
+        drop(mgr);
+
+        Ok(Listener::new(ip, port, conn, self.clone()))
+    }
+
+    // active open from local_ip, blocks until the handshake is over
+    fn connect(
+        &self,
+        local_ip: u32,
+        remote_ip: u32,
+        remote_port: u16,
+        timeout: Option<Duration>,
+    ) -> Result<ConnectionHandle> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        let port = self.ephemeral_port(local_ip, Some((remote_ip, remote_port)))?;
+        let mut conn = Connection::new(local_ip, port);
+        conn.client_ip = remote_ip;
+        conn.client_port = remote_port;
+        conn.state = State::SynSent;
+
+        let id = conn.id();
+        let shard = self.shard(&id);
+        let mut mgr = shard.mgr.lock().unwrap();
+        if mgr.conns.contains_key(&id) {
+            return Err(Error::AddrInUse);
+        }
+        let conn = mgr.insert(conn);
+        drop(mgr);
+        shard.wake();
+
+        let fail = |e: Error| {
+            shard
+                .mgr
+                .lock()
+                .unwrap()
+                .remove(&ConnectionKey::Conn(id.clone()));
+            Err(e)
+        };
+
+        loop {
+            let seen = conn.events.generation();
+            if let Err(e) = self.check_running() {
+                return fail(e);
+            }
+
+            let (state, timed_out) = {
+                let conn = conn.lock().unwrap();
+                (conn.state.clone(), conn.timed_out)
+            };
+            match state {
+                State::SynSent => {}
+                State::Closed if timed_out => return fail(Error::TimedOut),
+                State::Closed => return fail(Error::ConnectionRefused),
+                _ => break,
+            }
+
+            if !conn.events.wait(seen, deadline) {
+                return fail(Error::TimedOut);
+            }
+        }
+
+        conn.lock().unwrap().handles = 1;
+
+        Ok(ConnectionHandle {
+            mgr: self.clone(),
+            id,
+            conn,
+        })
+    }
+
+    fn accept(&self, listener: &Listener, timeout: Option<Duration>) -> Result<ConnectionHandle> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let nonblocking = listener.nonblocking();
+
+        loop {
+            let seen = listener.conn.events.generation();
+            self.check_running()?;
+            let mut listeners = self.listeners().mgr.lock().unwrap();
+            match self.try_accept(&mut listeners, listener.ip, listener.port) {
+                Err(Error::WouldBlock) if !nonblocking => {}
+                result => return result,
+            }
+            drop(listeners);
+
+            if !listener.conn.events.wait(seen, deadline) {
+                return Err(Error::TimedOut);
+            }
+        }
+    }
+
+    // WouldBlock until the listening connection has received a SYN. The
+    // connection moves to its own shard while `listeners` is still locked, so
+    // receive() never sees it in neither place
+    fn try_accept(&self, listeners: &mut Manager, ip: u32, port: u16) -> Result<ConnectionHandle> {
+        let conn = listeners.take_accepted(ip, port)?;
+        let id = conn.id();
+
+        let index = self.shard_index(&id);
+        let conn = match index {
+            0 => listeners.insert(conn),
+            _ => self.shards[index].mgr.lock().unwrap().insert(conn),
+        };
+        self.shards[index].wake();
+
+        Ok(ConnectionHandle {
+            mgr: self.clone(),
+            id,
+            conn,
+        })
+    }
+
+    // timers, retransmissions and queued data of one shard
+    fn tick(&self, shard: usize, device: &impl NetDevice) -> Result<()> {
+        let mut mgr = self.shards[shard].mgr.lock().unwrap();
+        mgr.on_tick();
+        let outgoing = mgr.outgoing.take();
+        drop(mgr);
+
+        self.send(device, outgoing)
+    }
+
+    fn send(&self, device: &impl NetDevice, outgoing: Vec<Vec<u8>>) -> Result<()> {
+        for packet in &outgoing {
+            match device.send(packet) {
+                Ok(_) => {}
+                Err(e) if transient(&e) => debug!("dropping a frame: {e}"),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.pool.put(outgoing);
+        Ok(())
+    }
+
+    // handles one frame that arrives on the queue of `shard` within
+    // timeout_ms, returns whether it did
+    fn receive(
+        &self,
+        shard: usize,
+        device: &impl NetDevice,
+        buf: &mut [u8],
+        timeout_ms: i32,
+    ) -> Result<bool> {
+        let pollfd = PollFd::new(device.as_raw_fd(), PollFlags::POLLIN);
+        if poll(&mut [pollfd], timeout_ms)? != 1 {
+            return Ok(false);
+        }
+
+        let recv_size = match device.recv(buf) {
+            Err(e) if transient(&e) => return Ok(true),
+            result => result?,
+        };
+        let Some(frame) = self.reassemble(&buf[..recv_size], device.offloads()) else {
+            return Ok(true);
+        };
+
+        let (owner, mut mgr) = self.lock_for(&frame, device.offloads());
+        if let Err(e) = mgr.on_packet(&frame) {
+            debug!("dropping packet: {e}");
+        }
+
+        // the segments of a connection all leave on the queue of its shard,
+        // the peer would see them out of order if some overtook the others
+        // on another queue. Without a wakeup the replies and whatever the
+        // frame made ready would wait for the next frame or timer of the shard
+        if owner != shard {
+            drop(mgr);
+            self.shards[owner].wake();
+            return Ok(true);
+        }
+
+        let outgoing = mgr.outgoing.take();
+        drop(mgr);
+
+        self.send(device, outgoing)?;
+        Ok(true)
+    }
+
+    // a frame that carries a whole datagram as it is, None for a fragment
+    // that does not complete its datagram. Only TCP is reassembled, other
+    // fragments and malformed ones are left to on_packet to drop
+    fn reassemble<'a>(&self, frame: &'a [u8], offloads: Offloads) -> Option<Cow<'a, [u8]>> {
+        let header_len = offloads.header_len();
+        if frame.len() < header_len || u16::from_be_bytes([frame[2], frame[3]]) != 0x0800 {
+            return Some(Cow::Borrowed(frame));
+        }
+
+        let packet = &frame[header_len..];
+        let fragment = IPv4Header::new(packet).is_ok_and(|(ip, _)| {
+            ip.is_fragment() && ip.protocol == 6 && ip.header_checksum == ip.calc_checksum()
+        });
+        if !fragment {
+            return Some(Cow::Borrowed(frame));
+        }
+
+        let now = self.clock.now();
+        match self.reassembly.lock().unwrap().insert(packet, now) {
+            Ok(Some(datagram)) => {
+                // the virtio-net header of the fragment does not apply to the
+                // datagram, the checksum has to be verified
+                let mut whole = frame[..header_len].to_vec();
+                whole[4..].fill(0);
+                whole.extend(datagram);
+                Some(Cow::Owned(whole))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                debug!("dropping fragment: {e}");
+                None
+            }
+        }
+    }
+
+    // the shard that has the frame's connection, or the one with the
+    // listeners if no shard has it (yet), and its index
+    fn lock_for(&self, frame: &[u8], offloads: Offloads) -> (usize, MutexGuard<'_, Manager>) {
+        let listeners = self.listeners();
+        let id = match connection_id(frame, offloads) {
+            Some(id) if self.shards.len() > 1 => id,
+            _ => return (0, listeners.mgr.lock().unwrap()),
+        };
+
+        let index = self.shard_index(&id);
+        let mgr = self.shards[index].mgr.lock().unwrap();
+        if index == 0 || mgr.conns.contains_key(&id) {
+            return (index, mgr);
+        }
+        drop(mgr);
+
+        // accept() may have moved the connection in the meantime, it does
+        // so with the listeners locked
+        let listeners = listeners.mgr.lock().unwrap();
+        let mgr = self.shards[index].mgr.lock().unwrap();
+        match mgr.conns.contains_key(&id) {
+            true => (index, mgr),
+            false => (0, listeners),
+        }
+    }
+
+    // sleeps in poll until a frame arrives on this queue, an application
+    // thread wakes the shard or its next timer is due, then handles at most
+    // one frame and runs on_tick
+    fn process_connections(&self, shard: usize, device: &impl NetDevice) -> Result<()> {
+        let mut buf = vec![0; device.offloads().max_frame(self.mtu)];
+
+        loop {
+            self.tick(shard, device)?;
+
+            let mut fds = [PollFd::new(device.as_raw_fd(), PollFlags::POLLIN); 2];
+            let wakeup = self.shards[shard].wakeup.as_ref();
+            let fds = match wakeup {
+                Some(wakeup) => {
+                    fds[1] = PollFd::new(wakeup.as_raw_fd(), PollFlags::POLLIN);
+                    &mut fds[..]
+                }
+                None => &mut fds[..1],
+            };
+            match poll(fds, self.poll_timeout(shard)) {
+                Err(Errno::EINTR) => continue,
+                result => result?,
+            };
+
+            if let Some(wakeup) = wakeup {
+                wakeup.clear();
+            }
+            self.receive(shard, device, &mut buf, 0)?;
+        }
+    }
+
+    // milliseconds until the next timer of the shard, rounded up so that it
+    // has expired when poll returns, -1 to wait forever
+    fn poll_timeout(&self, shard: usize) -> i32 {
+        let mgr = self.shards[shard].mgr.lock().unwrap();
+        let Some(deadline) = mgr.next_deadline() else {
+            return -1;
+        };
+
+        let wait = deadline.saturating_duration_since(mgr.clock.now());
+        wait.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+    }
+}
+
+impl Manager {
+    fn new(
+        clock: Arc<dyn Clock>,
+        pool: Arc<PacketPool>,
+        offloads: Offloads,
+        mtu: usize,
+    ) -> Manager {
+        let (ready_tx, ready) = mpsc::channel();
+        Manager {
+            conns: HashMap::new(),
+            listen: HashMap::new(),
+            outgoing: Outgoing::new(pool, offloads, mtu),
+            offloads,
+            timers: TimerWheel::new(clock.now()),
+            ready,
+            ready_tx,
+            clock,
+        }
+    }
+
+    // timers, retransmissions and queued data of the connections that are
+    // ready, the packets are left in outgoing
+    fn on_tick(&mut self) {
+        let now = self.clock.now();
+
+        for (timer, key) in self.timers.expire(now) {
+            if let Some(conn) = self.get(&key) {
+                let mut conn = conn.lock().unwrap();
+                if conn.timer.is_some_and(|(armed, _)| armed == timer) {
+                    conn.timer = None;
+                }
+            }
+            let _ = self.ready_tx.send(key);
+        }
+
+        // keys the application sends meanwhile are handled as well
+        while let Ok(key) = self.ready.try_recv() {
+            let Some(shared) = self.get(&key) else {
+                continue;
+            };
+
+            let mut conn = shared.lock().unwrap();
+            let state = conn.state.clone();
+            conn.on_tick(now, &mut self.outgoing);
+            // only a timeout changes what the application sees
+            if conn.state != state {
+                conn.wake_tasks();
+                shared.events.notify();
+            }
+            if conn.orphaned && conn.state == State::Closed {
+                drop(conn);
+                self.remove(&key);
+                continue;
+            }
+
+            conn.set_timer(key, &mut self.timers);
+        }
+    }
+
+    // the earliest time on_tick has something to do, None if it only has to
+    // run again after a packet or an application call
+    fn next_deadline(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    fn get(&self, key: &ConnectionKey) -> Option<SharedConnection> {
+        match key {
+            ConnectionKey::Conn(id) => self.conns.get(id),
+            ConnectionKey::Listen(addr) => self.listen.get(addr),
+        }
+        .cloned()
+    }
+
+    // handles that still point to the connection get NotConnected from now on
+    fn remove(&mut self, key: &ConnectionKey) -> Option<SharedConnection> {
+        let shared = match key {
+            ConnectionKey::Conn(id) => self.conns.remove(id),
+            ConnectionKey::Listen(addr) => self.listen.remove(addr),
+        }?;
+
+        let mut conn = shared.lock().unwrap();
+        conn.detached = true;
+        if let Some((timer, _)) = conn.timer.take() {
+            self.timers.cancel(timer);
+        }
+        drop(conn);
+        shared.events.notify();
+
+        Some(shared)
+    }
+
+    // makes the next on_tick look at the connection
+    fn mark_ready(&mut self, id: &ConnectionId) {
+        let _ = self.ready_tx.send(ConnectionKey::Conn(id.clone()));
+    }
+
+    // one frame read from the device, including the tun header, replies are
+    // left in outgoing. Frames that are not TCP over IPv4 are ignored
+    fn on_packet(&mut self, frame: &[u8]) -> Result<()> {
+        let header_len = self.offloads.header_len();
+        if frame.len() < header_len {
+            return Err(ParseError::TunTooShort(frame.len()).into());
+        }
+
+        // only allow IPv4, https://en.wikipedia.org/wiki/EtherType#Values
+        if u16::from_be_bytes([frame[2], frame[3]]) != 0x0800 {
+            return Ok(());
+        }
+
+        // locally generated frames come with a partial checksum, and the
+        // device may have verified the ones it received
+        let checksummed = self.offloads.vnet_hdr
+            && VnetHeader::new(&frame[4..]).is_some_and(|vnet| {
+                vnet.flags & (VnetHeader::NEEDS_CSUM | VnetHeader::DATA_VALID) != 0
+            });
+
+        let (ip, data) = IPv4Header::new(&frame[header_len..])?;
+        // only allow TCP, https://en.wikipedia.org/wiki/Internet_Protocol_version_4#Data
+        if ip.protocol != 6 {
+            return Ok(());
+        }
+        if ip.header_checksum != ip.calc_checksum() {
+            return Err(ParseError::Ipv4BadChecksum.into());
+        }
+        // ConnectionManager::receive reassembles them
+        if ip.is_fragment() {
+            return Err(ParseError::Ipv4Fragment.into());
+        }
+        ip.check_options()?;
+
+        let (tcp, data) = TcpHeader::new(data)?;
+        let now = self.clock.now();
+        if !checksummed
+            && tcp.checksum
+                != tcp.calc_checksum(ip.source_ip, ip.dest_ip, tcp.size() + data.len(), data)
+        {
+            return Err(ParseError::TcpBadChecksum.into());
+        }
+
+        let id = ConnectionId {
+            ip_src: ip.source_ip,
+            ip_dst: ip.dest_ip,
+            port_src: tcp.source_port,
+            port_dst: tcp.dest_port,
+        };
+
+        let addr = (ip.dest_ip, tcp.dest_port);
+        let key = match self.conns.contains_key(&id) {
+            true => ConnectionKey::Conn(id),
+            false => ConnectionKey::Listen(addr),
+        };
+        let Some(shared) = self.get(&key) else {
+            return Ok(());
+        };
+
+        let mut conn = shared.lock().unwrap();
+        let result = conn.on_message(data, &ip, &tcp, now, &mut self.outgoing);
+        conn.wake_tasks();
+        drop(conn);
+        shared.events.notify();
+
+        let _ = self.ready_tx.send(key);
+        result
+    }
+
+    // takes a listening connection that has received a SYN and starts
+    // listening again, WouldBlock if there is none yet. The caller insert()s
+    // it into the shard of its 4-tuple
+    // the listener stays the same object, so Listener handles and tasks
+    // waiting on it keep working
+    fn take_accepted(&mut self, ip: u32, port: u16) -> Result<Connection> {
+        let Some(listener) = self.listen.get(&(ip, port)) else {
+            return Err(Error::NotConnected);
+        };
+        let mut listener = listener.lock().unwrap();
+        if listener.state == State::Listen {
+            return Err(Error::WouldBlock);
+        }
+
+        let mut conn = std::mem::replace(&mut *listener, Connection::new(ip, port));
+        listener.read_waker = conn.read_waker.take();
+        drop(listener);
+
+        if let Some((timer, _)) = conn.timer.take() {
+            self.timers.cancel(timer);
+        }
+        conn.handles = 1;
+        Ok(conn)
+    }
+
+    // adds a connection to conns, the next on_tick sends whatever it has
+    // to send
+    fn insert(&mut self, conn: Connection) -> SharedConnection {
+        let id = conn.id();
+        let conn = Shared::new(conn);
+        self.conns.insert(id.clone(), conn.clone());
+        self.mark_ready(&id);
+        conn
+    }
+
+    // whether port on local_ip is listening or used towards `remote`, any
+    // remote if None
+    fn port_in_use(&self, local_ip: u32, port: u16, remote: Option<(u32, u16)>) -> bool {
+        self.listen.contains_key(&(local_ip, port))
+            || self.conns.keys().any(|id| {
+                id.ip_dst == local_ip
+                    && id.port_dst == port
+                    && remote.is_none_or(|r| r == (id.ip_src, id.port_src))
+            })
+    }
+
+    // a port on local_ip that is neither listening nor used towards `remote`
+    #[cfg_attr(not(feature = "fuzzing"), allow(dead_code))]
+    fn ephemeral_port(&self, local_ip: u32, remote: Option<(u32, u16)>) -> Result<u16> {
+        ephemeral_port(|port| self.port_in_use(local_ip, port, remote))
+    }
+}
+
+fn ephemeral_port(in_use: impl Fn(u16) -> bool) -> Result<u16> {
+    let mut rng = rand::thread_rng();
+    for _ in 0..EPHEMERAL_PORTS.len() {
+        let port = rng.gen_range(EPHEMERAL_PORTS);
+        if !in_use(port) {
+            return Ok(port);
+        }
+    }
+
+    Err(Error::AddrInUse)
+}
+
+// the MSS option of a SYN, DEFAULT_MSS if it has none. Options after a
+// malformed one are not looked at
+// device errors after which it keeps working, the frame is lost as on a
+// congested link and retransmission takes care of it. Anything else, like
+// EBADF or a closed device, stops the processing thread
+fn transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    ) || matches!(
+        e.raw_os_error().map(Errno::from_i32),
+        // a tun device whose link is down fails writes with EIO
+        Some(Errno::ENOBUFS | Errno::ENOMEM | Errno::EMSGSIZE | Errno::EIO)
+    )
+}
+
+fn peer_mss(tcp: &TcpHeader) -> usize {
+    tcp.parse_options()
+        .map_while(|option| option.ok())
+        .find_map(|option| match option {
+            TcpOption::Mss(mss) if mss > 0 => Some(mss as usize),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_MSS)
+}
+
+// the connection a frame belongs to, without checking it any further than
+// that, None for frames that are not TCP over IPv4
+fn connection_id(frame: &[u8], offloads: Offloads) -> Option<ConnectionId> {
+    let header_len = offloads.header_len();
+    if frame.len() < header_len || u16::from_be_bytes([frame[2], frame[3]]) != 0x0800 {
+        return None;
+    }
+
+    let (ip, data) = IPv4Header::new(&frame[header_len..]).ok()?;
+    if ip.protocol != 6 {
+        return None;
+    }
+    let (tcp, _) = TcpHeader::new(data).ok()?;
+
+    Some(ConnectionId {
+        ip_src: ip.source_ip,
+        ip_dst: ip.dest_ip,
+        port_src: tcp.source_port,
+        port_dst: tcp.dest_port,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{clock::VirtualClock, device::MemoryDevice};
+
+    const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+    const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    // two stepped stacks with a connection between them
+    struct Pair {
+        client: ConnectionHandle,
+        server: ConnectionHandle,
+        client_steps: Stepper<MemoryDevice>,
+        server_steps: Stepper<MemoryDevice>,
+    }
+
+    impl Pair {
+        fn connected() -> Pair {
+            let clock = Arc::new(VirtualClock::new());
+            let (server_dev, client_dev) = MemoryDevice::pair().unwrap();
+            let (server, mut server_steps) =
+                ConnectionManager::stepped(server_dev, clock.clone()).unwrap();
+            let (client, mut client_steps) =
+                ConnectionManager::stepped(client_dev, clock.clone()).unwrap();
+
+            let listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let connecting = thread::spawn(move || {
+                let timeout = Some(Duration::from_secs(10));
+                client.connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, timeout)
+            });
+
+            let mut accepted = None;
+            while accepted.is_none() || !connecting.is_finished() {
+                step(&mut client_steps, &mut server_steps);
+                accepted = accepted.or(listener.accept().ok());
+            }
+
+            Pair {
+                client: connecting.join().unwrap().unwrap(),
+                server: accepted.unwrap(),
+                client_steps,
+                server_steps,
+            }
+        }
+
+        fn step(&mut self) {
+            step(&mut self.client_steps, &mut self.server_steps);
+        }
+
+        fn state(conn: &ConnectionHandle) -> State {
+            conn.conn.lock().unwrap().state.clone()
+        }
+    }
+
+    // until neither stack has anything left to do
+    fn step(client: &mut Stepper<MemoryDevice>, server: &mut Stepper<MemoryDevice>) {
+        client.tick().unwrap();
+        server.tick().unwrap();
+        while client.step().unwrap() || server.step().unwrap() {}
+    }
+
+    fn states(mgr: &ConnectionManager) -> Vec<(u16, State)> {
+        let mgr = mgr.shards[0].mgr.lock().unwrap();
+        let mut states: Vec<_> = mgr
+            .conns
+            .iter()
+            .map(|(id, conn)| (id.port_src, conn.lock().unwrap().state.clone()))
+            .collect();
+        states.sort_by_key(|(port, _)| *port);
+        states
+    }
+
+    #[test]
+    fn abort_resets_the_peer_and_forgets_the_connection() {
+        let Pair {
+            client,
+            server,
+            mut client_steps,
+            mut server_steps,
+        } = Pair::connected();
+        let mgr = client.mgr.clone();
+        server.set_nonblocking(true).unwrap();
+
+        // queued data is discarded instead of being sent
+        (&client).write_all(b"never sent").unwrap();
+        client.abort();
+        assert!(states(&mgr).is_empty());
+
+        step(&mut client_steps, &mut server_steps);
+        let err = (&server).read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(Pair::state(&server), State::Closed);
+    }
+
+    #[test]
+    fn lingering_close_waits_for_the_ack_of_the_fin() {
+        let Pair {
+            client,
+            server,
+            mut client_steps,
+            mut server_steps,
+        } = Pair::connected();
+        client.set_linger(Some(Duration::from_secs(10))).unwrap();
+        (&client).write_all(b"bye").unwrap();
+
+        let closing = thread::spawn(move || client.close());
+        while !closing.is_finished() {
+            step(&mut client_steps, &mut server_steps);
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(closing.join().unwrap());
+
+        let mut buf = [0; 16];
+        let read = (&server).read(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"bye");
+        assert_eq!((&server).read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn lingering_close_gives_up_after_its_timeout() {
+        let pair = Pair::connected();
+        pair.client
+            .set_linger(Some(Duration::from_millis(50)))
+            .unwrap();
+        (&pair.client).write_all(b"bye").unwrap();
+        let conn = pair.client.conn.clone();
+
+        // nothing is stepped, so the data is never acknowledged
+        let started = Instant::now();
+        assert!(!pair.client.close());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        // and the connection keeps closing in the background
+        assert!(conn.lock().unwrap().closing);
+    }
+
+    #[test]
+    fn a_zero_linger_aborts() {
+        let Pair {
+            client,
+            server,
+            mut client_steps,
+            mut server_steps,
+        } = Pair::connected();
+        let mgr = client.mgr.clone();
+        client.set_linger(Some(Duration::ZERO)).unwrap();
+        (&client).write_all(b"bye").unwrap();
+
+        assert!(!client.close());
+        assert!(states(&mgr).is_empty());
+        step(&mut client_steps, &mut server_steps);
+        assert_eq!(Pair::state(&server), State::Closed);
+    }
+
+    #[test]
+    fn only_the_last_handle_closes_the_connection() {
+        let mut pair = Pair::connected();
+        let clone = pair.client.try_clone().unwrap();
+        let other = clone.try_clone().unwrap();
+
+        assert!(clone.close());
+        drop(other);
+        pair.step();
+        assert_eq!(Pair::state(&pair.client), State::Estab);
+
+        (&pair.client).write_all(b"still open").unwrap();
+        pair.step();
+        let mut buf = [0; 16];
+        let read = (&pair.server).read(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"still open");
+
+        let conn = pair.client.conn.clone();
+        assert!(pair.client.close());
+        step(&mut pair.client_steps, &mut pair.server_steps);
+        assert_eq!(conn.lock().unwrap().state, State::FinWait2);
+    }
+
+    #[test]
+    fn time_wait_expires_on_the_virtual_clock() {
+        let clock = Arc::new(VirtualClock::new());
+        let (server_dev, client_dev) = MemoryDevice::pair().unwrap();
+        let (server, mut server_steps) =
+            ConnectionManager::stepped(server_dev, clock.clone()).unwrap();
+        let (client, mut client_steps) =
+            ConnectionManager::stepped(client_dev, clock.clone()).unwrap();
+
+        let listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        // connect() blocks until the handshake is stepped through
+        let connecting = thread::spawn(move || {
+            let timeout = Some(Duration::from_secs(10));
+            client.connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, timeout)
+        });
+
+        let mut step = || {
+            client_steps.tick().unwrap();
+            server_steps.tick().unwrap();
+            while client_steps.step().unwrap() || server_steps.step().unwrap() {}
+        };
+
+        let mut accepted = None;
+        while accepted.is_none() || !connecting.is_finished() {
+            step();
+            accepted = accepted.or(listener.accept().ok());
+        }
+        let conn = connecting.join().unwrap().unwrap();
+        let client = conn.mgr.clone();
+
+        // the client closes first, so it is the one waiting in TIME-WAIT
+        conn.close();
+        while states(&client).first().map(|(_, s)| s) != Some(&State::FinWait2) {
+            step();
+        }
+        drop(accepted);
+        while states(&client).first().map(|(_, s)| s) != Some(&State::TimeWait) {
+            step();
+        }
+
+        for _ in 0..10 {
+            step();
+        }
+        clock.advance(TIME_WAIT - Duration::from_secs(1));
+        step();
+        assert_eq!(states(&client).len(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        step();
+        assert!(states(&client).is_empty());
+    }
+
+    // the peer sends no more than the receive buffer has room for, probes
+    // it while it is full and carries on once read() has made room
+    #[test]
+    fn the_receive_buffer_limits_what_the_peer_sends() {
+        let clock = Arc::new(VirtualClock::new());
+        let (server_dev, client_dev) = MemoryDevice::pair().unwrap();
+        let (server, mut server_steps) =
+            ConnectionManager::stepped(server_dev, clock.clone()).unwrap();
+        let (client, mut client_steps) =
+            ConnectionManager::stepped(client_dev, clock.clone()).unwrap();
+
+        let listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let connecting = thread::spawn(move || {
+            let timeout = Some(Duration::from_secs(10));
+            client.connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, timeout)
+        });
+
+        let mut step = || {
+            client_steps.tick().unwrap();
+            server_steps.tick().unwrap();
+            while client_steps.step().unwrap() || server_steps.step().unwrap() {}
+        };
+
+        let mut accepted = None;
+        while accepted.is_none() || !connecting.is_finished() {
+            step();
+            accepted = accepted.or(listener.accept().ok());
+        }
+        let (conn, accepted) = (connecting.join().unwrap().unwrap(), accepted.unwrap());
+        conn.set_nonblocking(true).unwrap();
+        accepted.set_nonblocking(true).unwrap();
+        let queued = || accepted.conn.lock().unwrap().recv_queue.len();
+
+        let data: Vec<u8> = (0..2 * RECV_BUFFER).map(|i| i as u8).collect();
+        let mut written = 0;
+        for _ in 0..200 {
+            written += (&conn).write(&data[written..]).unwrap_or(0);
+            step();
+        }
+        assert_eq!(queued(), RECV_BUFFER);
+        assert_eq!(conn.conn.lock().unwrap().send_window, 0);
+
+        // the probes are answered, and do not count as retransmissions
+        for _ in 0..=RETRIES {
+            clock.advance(MAX_RETRANSMIT);
+            step();
+        }
+        assert_eq!(queued(), RECV_BUFFER);
+        assert_eq!(conn.conn.lock().unwrap().state, State::Estab);
+
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        for _ in 0..200 {
+            while let Ok(read) = (&accepted).read(&mut buf) {
+                received.extend_from_slice(&buf[..read]);
+            }
+            written += (&conn).write(&data[written..]).unwrap_or(0);
+            step();
+        }
+        assert_eq!(received, data);
+    }
+
+    // past the 64^4 ms the timer wheel reaches, timers still wait for their
+    // deadline instead of firing right away
+    #[test]
+    fn timers_keep_their_deadlines_after_hours() {
+        let clock = Arc::new(VirtualClock::new());
+        let (server_dev, peer) = MemoryDevice::pair().unwrap();
+        let (server, mut steps) = ConnectionManager::stepped(server_dev, clock.clone()).unwrap();
+        let _listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+
+        clock.advance(Duration::from_secs(5 * 3600));
+        steps.tick().unwrap();
+
+        let id = ConnectionId {
+            ip_src: SERVER_IP.into(),
+            ip_dst: CLIENT_IP.into(),
+            port_src: 8080,
+            port_dst: 40000,
+        };
+        peer.send(&tcp::build_tcp_packet(&id, TcpFlag::Syn as u8, 0, 0, &[]))
+            .unwrap();
+        assert!(steps.step().unwrap());
+        steps.tick().unwrap();
+
+        // the SYN-ACK is only sent again once RETRANSMIT has passed
+        let mut buf = [0; 1504];
+        peer.recv(&mut buf).unwrap();
+        let timeout = server.poll_timeout(0);
+        assert!(timeout > 0 && timeout <= RETRANSMIT.as_millis() as i32);
+
+        clock.advance(RETRANSMIT);
+        steps.tick().unwrap();
+        let len = peer.recv(&mut buf).unwrap();
+        let (_, data) = IPv4Header::new(&buf[4..len]).unwrap();
+        let (tcp, _) = TcpHeader::new(data).unwrap();
+        assert!(tcp.get_flag(TcpFlag::Syn) && tcp.get_flag(TcpFlag::Ack));
+    }
+
+    // even without a timeout, connect() gives up on a peer that never answers
+    #[test]
+    fn unanswered_syns_time_out() {
+        let clock = Arc::new(VirtualClock::new());
+        let (client_dev, peer) = MemoryDevice::pair().unwrap();
+        let (client, mut steps) = ConnectionManager::stepped(client_dev, clock.clone()).unwrap();
+
+        let connecting =
+            thread::spawn(move || client.connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, None));
+
+        let mut syns = 0;
+        let mut buf = [0; 1504];
+        while !connecting.is_finished() {
+            steps.tick().unwrap();
+            let fd = PollFd::new(peer.as_raw_fd(), PollFlags::POLLIN);
+            while poll(&mut [fd], 0) == Ok(1) {
+                peer.recv(&mut buf).unwrap();
+                syns += 1;
+            }
+
+            clock.advance(Duration::from_secs(1));
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(matches!(connecting.join().unwrap(), Err(Error::TimedOut)));
+        assert_eq!(syns, 1 + RETRIES);
+    }
+
+    #[test]
+    fn mtus_outside_the_ipv4_range_are_rejected() {
+        for mtu in [60, 70000] {
+            let (dev, _) = MemoryDevice::pair_with_mtu(mtu).unwrap();
+            let result = ConnectionManager::new(dev);
+            assert!(matches!(result, Err(Error::InvalidInput(_))));
+
+            let (dev, _) = MemoryDevice::pair_with_mtu(mtu).unwrap();
+            let result = ConnectionManager::stepped(dev, Arc::new(VirtualClock::new()));
+            assert!(matches!(result, Err(Error::InvalidInput(_))));
+        }
+    }
+
+    // fails every send with `errno`
+    struct FailingDevice {
+        inner: MemoryDevice,
+        errno: Errno,
+        sends: Arc<Mutex<usize>>,
+    }
+
+    impl AsRawFd for FailingDevice {
+        fn as_raw_fd(&self) -> std::os::fd::RawFd {
+            self.inner.as_raw_fd()
+        }
+    }
+
+    impl NetDevice for FailingDevice {
+        fn send(&self, _frame: &[u8]) -> io::Result<usize> {
+            *self.sends.lock().unwrap() += 1;
+            Err(io::Error::from_raw_os_error(self.errno as i32))
+        }
+
+        fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.recv(buf)
+        }
+    }
+
+    #[test]
+    fn only_fatal_device_errors_stop_the_stack() {
+        for (errno, fatal) in [
+            (Errno::ENOBUFS, false),
+            (Errno::EAGAIN, false),
+            (Errno::EBADF, true),
+        ] {
+            let (inner, _peer) = MemoryDevice::pair().unwrap();
+            let sends = Arc::new(Mutex::new(0));
+            let device = FailingDevice {
+                inner,
+                errno,
+                sends: sends.clone(),
+            };
+            let clock = Arc::new(VirtualClock::new());
+            let (client, mut steps) = ConnectionManager::stepped(device, clock).unwrap();
+
+            let timeout = Some(Duration::from_millis(100));
+            let connecting = thread::spawn(move || {
+                client.connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, timeout)
+            });
+
+            // the SYN goes out on the first tick after connect() has queued it
+            let result = loop {
+                let result = steps.tick();
+                if *sends.lock().unwrap() > 0 {
+                    break result;
+                }
+                thread::sleep(Duration::from_millis(1));
+            };
+            assert_eq!(result.is_err(), fatal, "{errno}");
+            let _ = connecting.join();
+        }
+    }
+
+    #[test]
+    fn super_segments_leave_segmentation_to_the_device() {
+        let id = ConnectionId {
+            ip_src: 0x0a000002,
+            ip_dst: 0x0a000003,
+            port_src: 49152,
+            port_dst: 8080,
+        };
+        let offloads = Offloads {
+            vnet_hdr: true,
+            checksum: true,
+            tso: true,
+        };
+        let pool = Arc::new(PacketPool::new(offloads.header_len() + 1500));
+        let mut out = Outgoing::new(pool, offloads, 1500);
+        let text = vec![0; 3 * 1460];
+        out.data(&id, 1, 2, 1000, &[&text], 1460);
+        out.segment(&id, tcp::TcpFlag::Ack as u8, 1, 2, 1000, &[]);
+        // segments are cut to the smaller MSS of the two sides
+        out.data(&id, 1, 2, 1000, &[&text], 1000);
+        let frames = out.take();
+
+        let vnet = VnetHeader::new(&frames[0][4..]).unwrap();
+        assert_eq!(vnet.flags, VnetHeader::NEEDS_CSUM);
+        assert_eq!(vnet.gso_type, VnetHeader::GSO_TCPV4);
+        assert_eq!(vnet.gso_size, 1460);
+        assert_eq!((vnet.csum_start, vnet.csum_offset), (20, 16));
+        assert_eq!(frames[0].len(), offloads.header_len() + 40 + text.len());
+        assert_eq!(VnetHeader::new(&frames[2][4..]).unwrap().gso_size, 1000);
+
+        // a segment that fits needs no segmentation, only the checksum
+        let vnet = VnetHeader::new(&frames[1][4..]).unwrap();
+        assert_eq!(vnet.gso_type, VnetHeader::GSO_NONE);
+        assert_eq!(vnet.flags, VnetHeader::NEEDS_CSUM);
+    }
+
+    #[test]
+    fn datagrams_larger_than_the_mtu_are_fragmented() {
+        let id = ConnectionId {
+            ip_src: 0x0a000002,
+            ip_dst: 0x0a000003,
+            port_src: 49152,
+            port_dst: 8080,
+        };
+        let pool = Arc::new(PacketPool::new(4 + 576));
+        let mut out = Outgoing::new(pool, Offloads::default(), 576);
+        let text: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        out.segment(&id, TcpFlag::Ack as u8, 1, 2, 1000, &[&text]);
+        let frames = out.take();
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| frame.len() <= 4 + 576));
+
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        assert_eq!(reassembler.insert(&frames[1][4..], now).unwrap(), None);
+        let datagram = reassembler.insert(&frames[0][4..], now).unwrap().unwrap();
+
+        let (ip, data) = IPv4Header::new(&datagram).unwrap();
+        let (tcp, received) = TcpHeader::new(data).unwrap();
+        assert_eq!(received, text);
+        assert_eq!(
+            tcp.checksum,
+            tcp.calc_checksum(ip.source_ip, ip.dest_ip, data.len(), received)
+        );
+    }
+
+    // fragments of different datagrams must not be mixed up, by us or by a
+    // router that fragments them on the way
+    #[test]
+    fn every_datagram_has_its_own_identification() {
+        let id = ConnectionId {
+            ip_src: 0x0a000002,
+            ip_dst: 0x0a000003,
+            port_src: 49152,
+            port_dst: 8080,
+        };
+        let pool = Arc::new(PacketPool::new(4 + 576));
+        let mut out = Outgoing::new(pool, Offloads::default(), 576);
+        out.segment(&id, TcpFlag::Ack as u8, 1, 2, 1000, &[]);
+        out.data(&id, 1, 2, 1000, &[b"hello"], DEFAULT_MSS);
+        let text = [0; 1000];
+        out.segment(&id, TcpFlag::Ack as u8, 6, 2, 1000, &[&text]);
+        let frames = out.take();
+        assert_eq!(frames.len(), 4);
+
+        let ids: Vec<_> = frames
+            .iter()
+            .map(|frame| IPv4Header::new(&frame[4..]).unwrap().0.identification)
+            .collect();
+        assert_ne!(ids[0], ids[1]);
+        assert!(ids[2] != ids[0] && ids[2] != ids[1]);
+        // the fragments share the one of their datagram
+        assert_eq!(ids[2], ids[3]);
+    }
+
+    #[test]
+    fn fragmented_segments_are_reassembled() {
+        let clock = Arc::new(VirtualClock::new());
+        let (server_dev, peer) = MemoryDevice::pair().unwrap();
+        let (server, mut steps) = ConnectionManager::stepped(server_dev, clock).unwrap();
+        let _listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+
+        // a SYN from the peer, in three fragments that arrive last to first
+        let id = ConnectionId {
+            ip_src: SERVER_IP.into(),
+            ip_dst: CLIENT_IP.into(),
+            port_src: 8080,
+            port_dst: 40000,
+        };
+        let syn = tcp::build_tcp_packet(&id, TcpFlag::Syn as u8, 0, 0, &[]);
+        let mut fragments = Vec::new();
+        fragment::fragment(&syn[4..], 28, 1, |ip, payload| {
+            fragments.push([&syn[..4], ip, payload].concat());
+        })
+        .unwrap();
+        assert_eq!(fragments.len(), 3);
+
+        for fragment in fragments.iter().rev() {
+            peer.send(fragment).unwrap();
+            assert!(steps.step().unwrap());
+        }
+
+        let mut buf = [0; 1504];
+        let len = peer.recv(&mut buf).unwrap();
+        let (_, data) = IPv4Header::new(&buf[4..len]).unwrap();
+        let (tcp, _) = TcpHeader::new(data).unwrap();
+        assert!(tcp.get_flag(TcpFlag::Syn) && tcp.get_flag(TcpFlag::Ack));
+        assert_eq!(tcp.ack_number, 1);
+        assert_eq!(server.reassembly.lock().unwrap().memory(), 0);
+    }
+
+    // reads and writes only lock their own connection, not the Manager
+    #[test]
+    fn io_does_not_wait_for_the_manager() {
+        let (server_dev, client_dev) = MemoryDevice::pair().unwrap();
+        let server = ConnectionManager::new(server_dev).unwrap();
+        let client = ConnectionManager::new(client_dev).unwrap();
+
+        let listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+        let timeout = Some(Duration::from_secs(10));
+        let conn = client
+            .connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, timeout)
+            .unwrap();
+        let accepted = listener.accept().unwrap();
+        accepted.set_nonblocking(true).unwrap();
+
+        let (done, finished) = mpsc::channel();
+        let mgr = server.shards[0].mgr.lock().unwrap();
+        thread::spawn(move || {
+            let read = (&accepted).read(&mut [0; 16]).map_err(|e| e.kind());
+            (&conn).write_all(b"hello").unwrap();
+            done.send(read).unwrap();
+        });
+
+        let read = finished.recv_timeout(Duration::from_secs(5));
+        drop(mgr);
+        assert_eq!(read, Ok(Err(io::ErrorKind::WouldBlock)));
+    }
+
+    #[test]
+    fn pending_polls_keep_one_waker() {
+        use futures_io::AsyncRead;
+        use std::{
+            pin::Pin,
+            sync::atomic::{AtomicUsize, Ordering},
+            task::{Context, Wake},
+        };
+
+        struct Count(AtomicUsize);
+        impl Wake for Count {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let (server_dev, client_dev) = MemoryDevice::pair().unwrap();
+        let server = ConnectionManager::new(server_dev).unwrap();
+        let client = ConnectionManager::new(client_dev).unwrap();
+
+        let listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+        let timeout = Some(Duration::from_secs(10));
+        let conn = client
+            .connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, timeout)
+            .unwrap();
+        let mut accepted = listener.accept().unwrap();
+
+        let first = Arc::new(Count(AtomicUsize::new(0)));
+        let second = Arc::new(Count(AtomicUsize::new(0)));
+        let mut poll = |count: &Arc<Count>| {
+            let waker = Waker::from(count.clone());
+            let mut cx = Context::from_waker(&waker);
+            Pin::new(&mut accepted).poll_read(&mut cx, &mut [0; 16])
+        };
+
+        assert!(poll(&first).is_pending());
+        assert!(poll(&first).is_pending());
+        assert_eq!(Arc::strong_count(&first), 2);
+        // another task takes over the read side
+        assert!(poll(&second).is_pending());
+        assert_eq!(Arc::strong_count(&first), 1);
+
+        (&conn).write_all(b"hello").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while second.0.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(second.0.load(Ordering::SeqCst), 1);
+        assert_eq!(first.0.load(Ordering::SeqCst), 0);
+        assert!(matches!(poll(&second), std::task::Poll::Ready(Ok(5))));
+    }
+
+    // a frame wakes the calls blocked on its own connection only
+    #[test]
+    fn frames_only_wake_their_connection() {
+        let (server_dev, client_dev) = MemoryDevice::pair().unwrap();
+        let server = ConnectionManager::new(server_dev).unwrap();
+        let client = ConnectionManager::new(client_dev).unwrap();
+
+        let listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+        let timeout = Some(Duration::from_secs(10));
+        let pairs: Vec<_> = (0..2)
+            .map(|_| {
+                let conn = client
+                    .connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, timeout)
+                    .unwrap();
+                let accepted = listener.accept().unwrap();
+                // the handshake is over once data gets through
+                (&conn).write_all(b"x").unwrap();
+                (&accepted).read_exact(&mut [0; 1]).unwrap();
+                (conn, accepted)
+            })
+            .collect();
+
+        let (busy, idle) = (&pairs[0], &pairs[1]);
+        let seen = idle.1.conn.events.generation();
+        let busy_seen = busy.1.conn.events.generation();
+
+        (&busy.0).write_all(b"hello").unwrap();
+        (&busy.1).read_exact(&mut [0; 5]).unwrap();
+
+        assert_ne!(busy.1.conn.events.generation(), busy_seen);
+        assert_eq!(idle.1.conn.events.generation(), seen);
+    }
+
+    #[test]
+    fn poll_wakes_up_for_the_entry_that_changed() {
+        use crate::poll::{PollEntry, Readiness};
+
+        let (server_dev, client_dev) = MemoryDevice::pair().unwrap();
+        let server = ConnectionManager::new(server_dev).unwrap();
+        let client = ConnectionManager::new(client_dev).unwrap();
+
+        let listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+        let timeout = Some(Duration::from_secs(10));
+        let (conns, accepted): (Vec<_>, Vec<_>) = (0..2)
+            .map(|_| {
+                let conn = client
+                    .connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, timeout)
+                    .unwrap();
+                (conn, listener.accept().unwrap())
+            })
+            .unzip();
+
+        let mut entries: Vec<_> = accepted
+            .iter()
+            .map(|conn| PollEntry::connection(conn, Readiness::READABLE))
+            .collect();
+        let short = Some(Duration::from_millis(10));
+        assert_eq!(server.poll(&mut entries, short).unwrap(), 0);
+
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            (&conns[1]).write_all(b"hello").unwrap();
+            conns
+        });
+        assert_eq!(server.poll(&mut entries, timeout).unwrap(), 1);
+        assert!(entries[0].ready.is_empty());
+        assert_eq!(entries[1].ready, Readiness::READABLE);
+
+        // nothing stays registered once poll has returned
+        let events = &accepted[1].conn.events;
+        assert!(events.pollers.lock().unwrap().is_empty());
+        writer.join().unwrap();
+    }
+
+    // frames may arrive on any queue, each is handled by the shard that has
+    // its connection
+    #[test]
+    fn connections_are_spread_over_the_shards() {
+        let (server_devs, client_devs): (Vec<_>, Vec<_>) =
+            (0..4).map(|_| MemoryDevice::pair().unwrap()).unzip();
+        let server = ConnectionManager::with_queues(server_devs, Arc::new(SystemClock)).unwrap();
+        // both stacks put a connection on the same queue, but the server
+        // answers SYNs from the shard of its listeners, so the client gets
+        // the SYN-ACK on another queue than the one of the connection
+        let client = ConnectionManager::with_queues(client_devs, Arc::new(SystemClock)).unwrap();
+
+        let listener = server.listen(SERVER_IP.into(), 8080).unwrap();
+        let timeout = Some(Duration::from_secs(10));
+        let pairs: Vec<_> = (0..16)
+            .map(|_| {
+                let conn = client
+                    .connect(CLIENT_IP.into(), SERVER_IP.into(), 8080, timeout)
+                    .unwrap();
+                (conn, listener.accept().unwrap())
+            })
+            .collect();
+
+        for (i, (conn, accepted)) in pairs.iter().enumerate() {
+            (&*conn).write_all(&[i as u8; 3000]).unwrap();
+            let mut buf = [0; 3000];
+            (&*accepted).read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [i as u8; 3000]);
+        }
+
+        let used = server
+            .shards
+            .iter()
+            .filter(|shard| !shard.mgr.lock().unwrap().conns.is_empty())
+            .count();
+        assert!(used > 1);
+    }
+}
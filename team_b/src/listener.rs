@@ -1,27 +1,71 @@
-use crate::{ConnectionHandle, ConnectionManager};
+use std::{
+    net::SocketAddrV4,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{error::Result, ConnectionHandle, ConnectionKey, ConnectionManager, SharedConnection};
 
 pub struct Listener {
     pub ip: u32,
     pub port: u16,
     pub mgr: ConnectionManager,
+    // the listening connection, the Manager replaces its contents on accept
+    pub(crate) conn: SharedConnection,
+    nonblocking: AtomicBool,
 }
 
 impl Listener {
-    pub fn new(ip: u32, port: u16, mgr: ConnectionManager) -> Listener {
-        Listener { ip, port, mgr }
+    pub(crate) fn new(
+        ip: u32,
+        port: u16,
+        conn: SharedConnection,
+        mgr: ConnectionManager,
+    ) -> Listener {
+        Listener {
+            ip,
+            port,
+            mgr,
+            conn,
+            nonblocking: AtomicBool::new(false),
+        }
+    }
+
+    pub fn accept(&self) -> Result<ConnectionHandle> {
+        self.mgr.accept(self, None)
     }
 
-    pub fn accept(&mut self) -> ConnectionHandle {
-        loop {
-            if let Some(conn) = self.mgr.accept(self.ip, self.port) {
-                return conn;
-            }
+    // fails with TimedOut if nobody connected in time
+    pub fn accept_timeout(&self, timeout: Duration) -> Result<ConnectionHandle> {
+        self.mgr.accept(self, Some(timeout))
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.ip.into(), self.port)
+    }
+
+    // accept returns WouldBlock instead of waiting
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.mgr.check_running()?;
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn nonblocking(&self) -> bool {
+        self.nonblocking.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Ok(mut mgr) = self.mgr.listeners().mgr.lock() {
+            mgr.remove(&ConnectionKey::Listen((self.ip, self.port)));
         }
     }
 }
 
 impl Iterator for Listener {
-    type Item = ConnectionHandle;
+    type Item = Result<ConnectionHandle>;
 
     fn next(&mut self) -> Option<Self::Item> {
         Some(self.accept())
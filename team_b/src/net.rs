@@ -0,0 +1,253 @@
+// drop-in replacements for std::net::{TcpListener, TcpStream} running on top
+// of the user-space stack instead of the kernel one
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, ToSocketAddrs},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use crate::{device, listener::Listener, ConnectionHandle, ConnectionManager};
+
+// the address of the stack on tun0, see run.sh
+pub const LOCAL_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+// shared by everything created through the std-like constructors, runs on
+// tun0. If that fails the first time every later call fails the same way, an
+// io::Error can't be cloned so only its kind and message are kept
+pub fn default_manager() -> io::Result<&'static ConnectionManager> {
+    static MANAGER: OnceLock<Result<ConnectionManager, (ErrorKind, String)>> = OnceLock::new();
+
+    let mgr = MANAGER.get_or_init(|| {
+        let tun =
+            device::tun("tun0").map_err(|e| (e.kind(), format!("failed to open tun0: {e}")))?;
+        ConnectionManager::new(tun)
+            .map_err(|e| (e.kind(), format!("failed to start the tcp stack: {e}")))
+    });
+
+    mgr.as_ref()
+        .map_err(|(kind, msg)| io::Error::new(*kind, msg.as_str()))
+}
+
+fn resolve_v4<A: ToSocketAddrs>(addr: A) -> io::Result<Vec<SocketAddrV4>> {
+    let addrs: Vec<SocketAddrV4> = addr
+        .to_socket_addrs()?
+        .filter_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .collect();
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "could not resolve to any IPv4 addresses",
+        ));
+    }
+
+    Ok(addrs)
+}
+
+// std rejects zero timeouts instead of treating them as non-blocking
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+
+    Ok(())
+}
+
+pub struct TcpListener {
+    inner: Listener,
+}
+
+impl TcpListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        TcpListener::bind_with(default_manager()?, addr)
+    }
+
+    pub fn bind_with<A: ToSocketAddrs>(
+        mgr: &ConnectionManager,
+        addr: A,
+    ) -> io::Result<TcpListener> {
+        let mut last_err = None;
+
+        for addr in resolve_v4(addr)? {
+            match mgr.listen((*addr.ip()).into(), addr.port()) {
+                Ok(inner) => return Ok(TcpListener { inner }),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap().into())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.local_addr().into())
+    }
+
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let inner = self.inner.accept()?;
+        let addr = inner.peer_addr().into();
+
+        Ok((TcpStream { inner }, addr))
+    }
+
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        Ok(self.inner.set_nonblocking(nonblocking)?)
+    }
+}
+
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.listener.accept().map(|(stream, _)| stream))
+    }
+}
+
+pub struct TcpStream {
+    inner: ConnectionHandle,
+}
+
+impl TcpStream {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpStream> {
+        TcpStream::connect_with(default_manager()?, LOCAL_IP, addr, None)
+    }
+
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        check_timeout(Some(timeout))?;
+        TcpStream::connect_with(default_manager()?, LOCAL_IP, addr, Some(timeout))
+    }
+
+    // tries every resolved address in turn, like std does
+    pub fn connect_with<A: ToSocketAddrs>(
+        mgr: &ConnectionManager,
+        local_ip: Ipv4Addr,
+        addr: A,
+        timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        let mut last_err = None;
+
+        for addr in resolve_v4(addr)? {
+            match mgr.connect(local_ip.into(), (*addr.ip()).into(), addr.port(), timeout) {
+                Ok(inner) => return Ok(TcpStream { inner }),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap().into())
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.peer_addr().into())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner.local_addr().into())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        Ok(self.inner.shutdown(how)?)
+    }
+
+    pub fn try_clone(&self) -> io::Result<TcpStream> {
+        Ok(TcpStream {
+            inner: self.inner.try_clone()?,
+        })
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        Ok(self.inner.set_read_timeout(timeout)?)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        Ok(self.inner.set_write_timeout(timeout)?)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.inner.read_timeout()?)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.inner.write_timeout()?)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        Ok(self.inner.set_nonblocking(nonblocking)?)
+    }
+
+    pub fn set_linger(&self, linger: Option<Duration>) -> io::Result<()> {
+        Ok(self.inner.set_linger(linger)?)
+    }
+
+    pub fn linger(&self) -> io::Result<Option<Duration>> {
+        Ok(self.inner.linger()?)
+    }
+
+    // the stack never delays small segments, so Nagle is always off
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        if !nodelay {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Nagle's algorithm is not implemented",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn nodelay(&self) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    pub fn into_inner(self) -> ConnectionHandle {
+        self.inner
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.inner).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.inner).flush()
+    }
+}
+
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.inner).read(buf)
+    }
+}
+
+impl Write for &TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.inner).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.inner).flush()
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    ops::BitAnd,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::Result,
+    events::{Events, Poller},
+    listener::Listener,
+    ConnectionHandle, ConnectionManager, State,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Readiness {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Readiness {
+    pub const READABLE: Readiness = Readiness {
+        readable: true,
+        writable: false,
+    };
+    pub const WRITABLE: Readiness = Readiness {
+        readable: false,
+        writable: true,
+    };
+    pub const BOTH: Readiness = Readiness {
+        readable: true,
+        writable: true,
+    };
+
+    pub fn is_empty(&self) -> bool {
+        !self.readable && !self.writable
+    }
+}
+
+impl BitAnd for Readiness {
+    type Output = Readiness;
+
+    fn bitand(self, rhs: Readiness) -> Self::Output {
+        Readiness {
+            readable: self.readable && rhs.readable,
+            writable: self.writable && rhs.writable,
+        }
+    }
+}
+
+pub enum Source<'a> {
+    Listener(&'a Listener),
+    Connection(&'a ConnectionHandle),
+}
+
+// one handle to wait on, `ready` is filled in by ConnectionManager::poll
+pub struct PollEntry<'a> {
+    pub source: Source<'a>,
+    pub interest: Readiness,
+    pub ready: Readiness,
+}
+
+impl<'a> PollEntry<'a> {
+    pub fn listener(listener: &'a Listener) -> PollEntry<'a> {
+        PollEntry {
+            source: Source::Listener(listener),
+            interest: Readiness::READABLE,
+            ready: Readiness::default(),
+        }
+    }
+
+    pub fn connection(conn: &'a ConnectionHandle, interest: Readiness) -> PollEntry<'a> {
+        PollEntry {
+            source: Source::Connection(conn),
+            interest,
+            ready: Readiness::default(),
+        }
+    }
+}
+
+// a closed, reset or missing connection is both readable and writable, the
+// next call will report the error without blocking
+fn readiness(source: &Source) -> Readiness {
+    match source {
+        Source::Listener(listener) => Readiness {
+            readable: listener.conn.lock().unwrap().state != State::Listen,
+            writable: false,
+        },
+        Source::Connection(handle) => {
+            let conn = handle.conn.lock().unwrap();
+            match conn.detached {
+                false => Readiness {
+                    readable: conn.readable(),
+                    writable: conn.writable(),
+                },
+                true => Readiness::BOTH,
+            }
+        }
+    }
+}
+
+impl Source<'_> {
+    fn events(&self) -> &Events {
+        match self {
+            Source::Listener(listener) => &listener.conn.events,
+            Source::Connection(handle) => &handle.conn.events,
+        }
+    }
+}
+
+impl ConnectionManager {
+    // like poll(2): waits until at least one entry is ready or the timeout
+    // expires and returns the number of ready entries, 0 on timeout
+    pub fn poll(&self, entries: &mut [PollEntry], timeout: Option<Duration>) -> Result<usize> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        // every entry is looked at once, after that only the ones whose
+        // connection has changed
+        let poller = Arc::new(Poller::default());
+        for (index, entry) in entries.iter_mut().enumerate() {
+            entry.source.events().register(&poller, index);
+            entry.ready = Readiness::default();
+        }
+
+        let result = self.wait_ready(entries, &poller, deadline);
+
+        for entry in entries.iter() {
+            entry.source.events().unregister(&poller);
+        }
+        result
+    }
+
+    fn wait_ready(
+        &self,
+        entries: &mut [PollEntry],
+        poller: &Poller,
+        deadline: Option<Instant>,
+    ) -> Result<usize> {
+        let mut changed: Vec<usize> = (0..entries.len()).collect();
+        let mut ready = 0;
+
+        loop {
+            self.check_running()?;
+
+            for index in changed {
+                let entry = &mut entries[index];
+                let was_ready = !entry.ready.is_empty();
+                entry.ready = readiness(&entry.source) & entry.interest;
+                match (was_ready, !entry.ready.is_empty()) {
+                    (false, true) => ready += 1,
+                    (true, false) => ready -= 1,
+                    _ => {}
+                }
+            }
+
+            if ready > 0 {
+                return Ok(ready);
+            }
+
+            changed = poller.wait(deadline);
+            if changed.is_empty() {
+                return Ok(0);
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+// frame buffers are reused instead of allocated for every segment: the
+// processing thread builds frames in buffers from the pool, sends them and
+// puts the buffers back, together with the list they were queued in
+
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+use rand::Rng;
+
+use crate::{
+    device::{Offloads, VnetHeader},
+    fragment::fragment,
+    tcp::{write_tcp_packet, TcpFlag, TcpOption},
+    utils::ConnectionId,
+};
+
+// more than a full send window of MSS sized segments
+const POOLED: usize = 256;
+
+#[derive(Debug)]
+pub(crate) struct PacketPool {
+    // the capacity of new buffers, a full sized frame. Super segments for
+    // TSO grow the buffer they are built in
+    frame: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+    lists: Mutex<Vec<Vec<Vec<u8>>>>,
+}
+
+impl PacketPool {
+    // for frames of up to `frame` bytes, headers included
+    pub(crate) fn new(frame: usize) -> PacketPool {
+        PacketPool {
+            frame,
+            free: Mutex::new(Vec::new()),
+            lists: Mutex::new(Vec::new()),
+        }
+    }
+
+    // an empty buffer with room for a full sized frame
+    pub(crate) fn get(&self) -> Vec<u8> {
+        self.free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.frame))
+    }
+
+    // gives back the buffers of frames that have been sent and the list itself
+    pub(crate) fn put(&self, mut frames: Vec<Vec<u8>>) {
+        let mut free = self.free.lock().unwrap();
+        for mut frame in frames.drain(..) {
+            if free.len() == POOLED {
+                break;
+            }
+            frame.clear();
+            free.push(frame);
+        }
+        drop(free);
+
+        let mut lists = self.lists.lock().unwrap();
+        if lists.len() < POOLED {
+            lists.push(frames);
+        }
+    }
+
+    fn list(&self) -> Vec<Vec<u8>> {
+        self.lists.lock().unwrap().pop().unwrap_or_default()
+    }
+}
+
+// frames waiting to be sent, in the format of the device
+#[derive(Debug)]
+pub(crate) struct Outgoing {
+    pub(crate) frames: Vec<Vec<u8>>,
+    pool: Arc<PacketPool>,
+    offloads: Offloads,
+    mtu: usize,
+    // the largest segment that fits into the MTU of the device, advertised
+    // in SYNs
+    mss: usize,
+    // of the next datagram, every one gets its own so that fragments, ours
+    // or those of a router on the way, are never mixed up
+    identification: u16,
+}
+
+impl Outgoing {
+    pub(crate) fn new(pool: Arc<PacketPool>, offloads: Offloads, mtu: usize) -> Outgoing {
+        Outgoing {
+            frames: Vec::new(),
+            pool,
+            offloads,
+            mtu,
+            mss: mtu - 40,
+            identification: rand::thread_rng().gen(),
+        }
+    }
+
+    // the most data one data() may carry towards a peer that accepts
+    // segments of up to `peer_mss`, the device cuts larger ones into
+    // segments of that size
+    pub(crate) fn max_segment(&self, peer_mss: usize) -> usize {
+        match self.offloads.tso {
+            true => u16::MAX as usize - 40,
+            false => self.mss.min(peer_mss),
+        }
+    }
+
+    // a segment without data, or with at most the MSS of both sides
+    pub(crate) fn segment(
+        &mut self,
+        id: &ConnectionId,
+        flags: u8,
+        seq_num: u32,
+        ack_num: u32,
+        window: u16,
+        text: &[&[u8]],
+    ) {
+        self.push(id, flags, seq_num, ack_num, window, &[], text, self.mss);
+    }
+
+    // a segment of up to max_segment(peer_mss)
+    pub(crate) fn data(
+        &mut self,
+        id: &ConnectionId,
+        seq_num: u32,
+        ack_num: u32,
+        window: u16,
+        text: &[&[u8]],
+        peer_mss: usize,
+    ) {
+        let mss = self.mss.min(peer_mss);
+        let flags = TcpFlag::Ack as u8;
+        self.push(id, flags, seq_num, ack_num, window, &[], text, mss);
+    }
+
+    // a SYN or SYN-ACK, with our MSS
+    pub(crate) fn syn(
+        &mut self,
+        id: &ConnectionId,
+        flags: u8,
+        seq_num: u32,
+        ack_num: u32,
+        window: u16,
+    ) {
+        let mss = TcpOption::Mss(self.mss as u16).serialize();
+        self.push(id, flags, seq_num, ack_num, window, &mss, &[], self.mss);
+    }
+
+    // see write_tcp_packet, text beyond `mss` is left to TSO. Without TSO a
+    // datagram larger than the MTU is sent in fragments
+    #[allow(clippy::too_many_arguments)]
+    fn push(
+        &mut self,
+        id: &ConnectionId,
+        flags: u8,
+        seq_num: u32,
+        ack_num: u32,
+        window: u16,
+        options: &[u8],
+        text: &[&[u8]],
+        mss: usize,
+    ) {
+        let mut frame = self.pool.get();
+        // the tun header: no flags, IPv4
+        frame.extend([0, 0, 0x08, 0x00]);
+        let header_len = self.offloads.header_len();
+        frame.resize(header_len, 0);
+
+        let text_len: usize = text.iter().map(|piece| piece.len()).sum();
+        let fragmented = 40 + options.len() + text_len > self.mtu && !self.offloads.tso;
+        // the device cannot complete the checksum of a fragmented segment
+        let checksum = self.offloads.checksum && !fragmented;
+        let identification = self.identification;
+        self.identification = self.identification.wrapping_add(1);
+        write_tcp_packet(
+            &mut frame,
+            id,
+            flags,
+            seq_num,
+            ack_num,
+            window,
+            options,
+            text,
+            identification,
+            checksum,
+        );
+        if fragmented {
+            self.fragment(frame, identification);
+            return;
+        }
+
+        if self.offloads.vnet_hdr {
+            let headers = 40 + options.len();
+            let gso = frame.len() - header_len - headers > mss;
+            VnetHeader {
+                flags: if checksum { VnetHeader::NEEDS_CSUM } else { 0 },
+                gso_type: if gso {
+                    VnetHeader::GSO_TCPV4
+                } else {
+                    VnetHeader::GSO_NONE
+                },
+                hdr_len: headers as u16,
+                gso_size: if gso { mss as u16 } else { 0 },
+                // relative to the IP header
+                csum_start: if checksum { 20 } else { 0 },
+                csum_offset: if checksum { 16 } else { 0 },
+            }
+            .serialize_into(&mut frame[4..]);
+        }
+
+        self.frames.push(frame);
+    }
+
+    // replaces `frame` with frames of its fragments, which leave the
+    // virtio-net header empty
+    fn fragment(&mut self, frame: Vec<u8>, identification: u16) {
+        let header_len = self.offloads.header_len();
+
+        let result = fragment(
+            &frame[header_len..],
+            self.mtu,
+            identification,
+            |ip, payload| {
+                let mut piece = self.pool.get();
+                piece.extend_from_slice(&frame[..header_len]);
+                piece.extend_from_slice(ip);
+                piece.extend_from_slice(payload);
+                self.frames.push(piece);
+            },
+        );
+        if let Err(e) = result {
+            debug!("cannot fragment the datagram: {e}");
+        }
+
+        self.pool.put(vec![frame]);
+    }
+
+    // the caller gives the frames back with PacketPool::put once they are sent
+    pub(crate) fn take(&mut self) -> Vec<Vec<u8>> {
+        let list = self.pool.list();
+        std::mem::replace(&mut self.frames, list)
+    }
+}
@@ -0,0 +1,31 @@
+use crate::{device::NetDevice, error::Result, ConnectionManager};
+
+// does the work of the processing thread for a ConnectionManager created
+// with ConnectionManager::stepped, one event at a time
+pub struct Stepper<D> {
+    mgr: ConnectionManager,
+    device: D,
+    buf: Vec<u8>,
+}
+
+impl<D: NetDevice> Stepper<D> {
+    pub(crate) fn new(mgr: ConnectionManager, device: D) -> Stepper<D> {
+        Stepper {
+            mgr,
+            buf: vec![0; device.offloads().max_frame(device.mtu())],
+            device,
+        }
+    }
+
+    // runs the timers and sends whatever is due, like one round of the
+    // processing loop does
+    pub fn tick(&mut self) -> Result<()> {
+        self.mgr.tick(0, &self.device)
+    }
+
+    // handles one frame if one is already waiting on the device, returns
+    // whether there was one
+    pub fn step(&mut self) -> Result<bool> {
+        self.mgr.receive(0, &self.device, &mut self.buf, 0)
+    }
+}
@@ -1,9 +1,14 @@
 use std::ops::BitOr;
 
-use anyhow::Result;
+use checksum::Checksum;
+
+use crate::error::{ParseError, Result};
 
 use crate::{ipv4, utils::*};
 
+// advertised by build_tcp_packet, as large as it gets without window scaling
+const WINDOW: u16 = u16::MAX;
+
 pub enum TcpFlag {
     Cwr = 0b10000000,
     Ece = 0b01000000,
@@ -23,7 +28,7 @@ impl BitOr<TcpFlag> for TcpFlag {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct TcpHeader<'a> {
     pub source_port: u16,
     pub dest_port: u16,
@@ -39,23 +44,34 @@ pub struct TcpHeader<'a> {
 
 impl<'a> TcpHeader<'a> {
     // https://en.wikipedia.org/wiki/Transmission_Control_Protocol#TCP_segment_structure
-    pub fn new(data: &'a [u8]) -> Result<(TcpHeader<'a>, &[u8])> {
+    pub fn new(data: &'a [u8]) -> Result<(TcpHeader<'a>, &'a [u8])> {
+        if data.len() < 20 {
+            return Err(ParseError::TcpTooShort(data.len()).into());
+        }
+
         let data_offset = data[12] >> 4;
+        let header_len = data_offset as usize * 4;
+        if data_offset < 5 {
+            return Err(ParseError::TcpBadDataOffset(data_offset).into());
+        }
+        if header_len > data.len() {
+            return Err(ParseError::TcpTruncatedOptions(data_offset).into());
+        }
 
         Ok((
             TcpHeader {
-                source_port: u16::from_be_bytes(data[0..2].try_into()?),
-                dest_port: u16::from_be_bytes(data[2..4].try_into()?),
-                sequence_number: u32::from_be_bytes(data[4..8].try_into()?),
-                ack_number: u32::from_be_bytes(data[8..12].try_into()?),
+                source_port: u16::from_be_bytes([data[0], data[1]]),
+                dest_port: u16::from_be_bytes([data[2], data[3]]),
+                sequence_number: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+                ack_number: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
                 data_offset,
                 flags: data[13],
-                window_size: u16::from_be_bytes(data[14..16].try_into()?),
-                checksum: u16::from_be_bytes(data[16..18].try_into()?),
-                urgent_pointer: u16::from_be_bytes(data[18..20].try_into()?),
-                options: &data[20..data_offset as usize * 4],
+                window_size: u16::from_be_bytes([data[14], data[15]]),
+                checksum: u16::from_be_bytes([data[16], data[17]]),
+                urgent_pointer: u16::from_be_bytes([data[18], data[19]]),
+                options: &data[20..header_len],
             },
-            &data[data_offset as usize * 4..],
+            &data[header_len..],
         ))
     }
 
@@ -63,9 +79,18 @@ impl<'a> TcpHeader<'a> {
         self.data_offset as usize * 4
     }
 
+    pub fn parse_options(&self) -> TcpOptions<'a> {
+        TcpOptions { data: self.options }
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
-        let mut data = vec![0; 56];
+        let mut data = vec![0; 60];
+        self.serialize_into(&mut data);
+        data
+    }
 
+    // writes the size() bytes of the header to the start of `data`
+    pub fn serialize_into(&self, data: &mut [u8]) {
         set_u16_be(&mut data[0..2], self.source_port);
         set_u16_be(&mut data[2..4], self.dest_port);
         set_u32_be(&mut data[4..8], self.sequence_number);
@@ -76,8 +101,6 @@ impl<'a> TcpHeader<'a> {
         set_u16_be(&mut data[16..18], self.checksum);
         set_u16_be(&mut data[18..20], self.urgent_pointer);
         data[20..self.size()].copy_from_slice(self.options);
-
-        data
     }
 
     pub fn calc_checksum(
@@ -87,47 +110,112 @@ impl<'a> TcpHeader<'a> {
         data_length: usize,
         text: &[u8],
     ) -> u16 {
-        let mut data = vec![0; data_length + 12];
-        let mut sum = 0;
-
-        set_u32_be(&mut data[0..4], source_ip);
-        set_u32_be(&mut data[4..8], dest_ip);
-        data[9] = 6; // protocol
-        set_u16_be(&mut data[10..12], data_length as u16);
-        data[12..self.size() + 12].copy_from_slice(&self.serialize()[0..self.size()]);
-        data[self.size() + 12..data_length + 12].copy_from_slice(text);
-
-        if data.len() % 2 != 0 {
-            data.push(0);
-        }
-
-        for i in (0..data.len()).step_by(2) {
-            if i == 28 {
-                continue;
-            }; // ignore the checksum field
+        let mut header = [0; 60];
+        self.serialize_into(&mut header);
 
-            sum += u16::from_be_bytes(data[i..i + 2].try_into().unwrap()) as u32;
-
-            sum += sum >> 16;
-            sum &= 0x0000FFFF;
-        }
-
-        !sum as u16
+        // skips the checksum field
+        pseudo_header(source_ip, dest_ip, data_length)
+            .add_bytes(&header[..16])
+            .add_bytes(&header[18..self.size()])
+            .add_bytes(text)
+            .finish()
     }
 
     pub fn get_flag(&self, flag: TcpFlag) -> bool {
         self.flags & flag as u8 != 0
     }
+}
+
+// https://www.iana.org/assignments/tcp-parameters/tcp-parameters.xhtml#tcp-parameters-1
+#[derive(Debug, PartialEq, Eq)]
+pub enum TcpOption<'a> {
+    Nop,
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    // value, echo reply
+    Timestamps(u32, u32),
+    Unknown(u8, &'a [u8]),
+}
 
-    pub fn set_flag(&mut self, flag: TcpFlag, value: bool) {
-        if value {
-            self.flags |= flag as u8
-        } else {
-            self.flags &= !(flag as u8)
+impl TcpOption<'_> {
+    pub fn serialize(&self) -> Vec<u8> {
+        match self {
+            TcpOption::Nop => vec![1],
+            TcpOption::Mss(mss) => [&[2, 4][..], &mss.to_be_bytes()].concat(),
+            TcpOption::WindowScale(shift) => vec![3, 3, *shift],
+            TcpOption::SackPermitted => vec![4, 2],
+            TcpOption::Timestamps(value, echo) => {
+                [&[8, 10][..], &value.to_be_bytes(), &echo.to_be_bytes()].concat()
+            }
+            TcpOption::Unknown(kind, value) => {
+                [&[*kind, value.len() as u8 + 2][..], value].concat()
+            }
         }
     }
 }
 
+// stops at the end of option list, or after the first malformed option
+pub struct TcpOptions<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for TcpOptions<'a> {
+    type Item = Result<TcpOption<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&kind, rest) = self.data.split_first()?;
+        match kind {
+            0 => {
+                self.data = &[];
+                return None;
+            }
+            1 => {
+                self.data = rest;
+                return Some(Ok(TcpOption::Nop));
+            }
+            _ => {}
+        }
+
+        // the length includes the kind and length bytes
+        let len = match rest.first() {
+            Some(&len) if len >= 2 && len as usize <= self.data.len() => len as usize,
+            _ => {
+                self.data = &[];
+                return Some(Err(ParseError::TcpBadOption(kind).into()));
+            }
+        };
+        let value = &self.data[2..len];
+        self.data = &self.data[len..];
+
+        let option = match (kind, value) {
+            (2, &[a, b]) => TcpOption::Mss(u16::from_be_bytes([a, b])),
+            (3, &[shift]) => TcpOption::WindowScale(shift),
+            (4, &[]) => TcpOption::SackPermitted,
+            (8, &[a, b, c, d, e, f, g, h]) => TcpOption::Timestamps(
+                u32::from_be_bytes([a, b, c, d]),
+                u32::from_be_bytes([e, f, g, h]),
+            ),
+            (2 | 3 | 4 | 8, _) => {
+                self.data = &[];
+                return Some(Err(ParseError::TcpBadOption(kind).into()));
+            }
+            (kind, value) => TcpOption::Unknown(kind, value),
+        };
+
+        Some(Ok(option))
+    }
+}
+
+// https://en.wikipedia.org/wiki/Transmission_Control_Protocol#TCP_checksum_for_IPv4
+fn pseudo_header(source_ip: u32, dest_ip: u32, data_length: usize) -> Checksum {
+    Checksum::new()
+        .add_u32(source_ip)
+        .add_u32(dest_ip)
+        .add_u16(6) // protocol
+        .add_u16(data_length as u16)
+}
+
 pub fn build_tcp_packet(
     id: &ConnectionId,
     flags: u8,
@@ -135,26 +223,67 @@ pub fn build_tcp_packet(
     ack_num: u32,
     text: &[u8],
 ) -> Vec<u8> {
-    let mut tcp = TcpHeader {
+    // the tun header: no flags, IPv4
+    let mut frame = vec![0, 0, 0x08, 0x00];
+    write_tcp_packet(
+        &mut frame,
+        id,
+        flags,
+        seq_num,
+        ack_num,
+        WINDOW,
+        &[],
+        &[text],
+        0,
+        false,
+    );
+    frame
+}
+
+// appends the IP packet of build_tcp_packet to `frame`, building the headers
+// in place so that a reused buffer needs no allocation. The text is given in
+// pieces, e.g. the two halves of a VecDeque, and is copied exactly once.
+// The options have to be padded to a multiple of 4 bytes
+// `identification` tells the datagram apart from the other ones of the
+// source, in case it gets fragmented on the way
+// with `partial_checksum` the TCP checksum field only gets the pseudo header
+// sum, for a device that completes the checksum
+#[allow(clippy::too_many_arguments)]
+pub fn write_tcp_packet(
+    frame: &mut Vec<u8>,
+    id: &ConnectionId,
+    flags: u8,
+    seq_num: u32,
+    ack_num: u32,
+    window: u16,
+    options: &[u8],
+    text: &[&[u8]],
+    identification: u16,
+    partial_checksum: bool,
+) {
+    let text_len: usize = text.iter().map(|piece| piece.len()).sum();
+    let tcp_len = 20 + options.len();
+
+    let tcp = TcpHeader {
         source_port: id.port_dst,
         dest_port: id.port_src,
         sequence_number: seq_num,
         ack_number: ack_num,
-        data_offset: 0,
+        data_offset: tcp_len as u8 / 4,
         flags,
-        window_size: 1500,
-        checksum: 0,
+        window_size: window,
+        checksum: 0, // filled out later
         urgent_pointer: 0,
-        options: &[0; 0],
+        options,
     };
 
-    let mut ip = ipv4::IPv4Header {
+    let ip = ipv4::IPv4Header {
         version: 4,
         ihl: 5,
         dscp: 0,
         ecn: 0,
-        total_length: 40 + text.len() as u16,
-        identification: 0,
+        total_length: (20 + tcp_len + text_len) as u16,
+        identification,
         flags: 0b000,
         fragment_offset: 0,
         time_to_live: 64,
@@ -164,15 +293,146 @@ pub fn build_tcp_packet(
         dest_ip: id.ip_src,
         options: &[0; 0],
     };
-    ip.header_checksum = ip.calc_checksum();
 
-    tcp.data_offset = 5;
-    tcp.checksum = tcp.calc_checksum(ip.source_ip, ip.dest_ip, tcp.size() + text.len(), text);
+    let start = frame.len();
+    frame.resize(start + 20 + tcp_len, 0);
+    let (ip_header, tcp_header) = (start..start + 20, start + 20..start + 20 + tcp_len);
+    ip.serialize_into(&mut frame[ip_header.clone()]);
+    tcp.serialize_into(&mut frame[tcp_header.clone()]);
+    for piece in text {
+        frame.extend_from_slice(piece);
+    }
+
+    // both checksum fields are still zero
+    let checksum = checksum::checksum(&frame[ip_header]);
+    set_u16_be(&mut frame[start + 10..start + 12], checksum);
+
+    let pseudo_header = pseudo_header(ip.source_ip, ip.dest_ip, tcp_len + text_len);
+    let checksum = match partial_checksum {
+        true => pseudo_header.sum(),
+        false => pseudo_header.add_bytes(&frame[tcp_header.start..]).finish(),
+    };
+    set_u16_be(&mut frame[start + 36..start + 38], checksum);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let options = [
+            TcpOption::Mss(1460),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamps(1, 2),
+            TcpOption::Nop,
+            TcpOption::WindowScale(7),
+        ]
+        .iter()
+        .flat_map(TcpOption::serialize)
+        .collect::<Vec<_>>();
+
+        let tcp = TcpHeader {
+            source_port: 49152,
+            dest_port: 8080,
+            sequence_number: 0xdeadbeef,
+            ack_number: 42,
+            data_offset: 5 + options.len() as u8 / 4,
+            flags: TcpFlag::Syn | TcpFlag::Ack,
+            window_size: 1500,
+            checksum: 0xabcd,
+            urgent_pointer: 0,
+            options: &options,
+        };
+
+        let mut segment = tcp.serialize()[..tcp.size()].to_vec();
+        segment.extend(b"data");
+
+        let (parsed, text) = TcpHeader::new(&segment).unwrap();
+        assert_eq!(parsed, tcp);
+        assert_eq!(text, b"data");
+
+        let parsed = parsed.parse_options().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            parsed,
+            [
+                TcpOption::Mss(1460),
+                TcpOption::SackPermitted,
+                TcpOption::Timestamps(1, 2),
+                TcpOption::Nop,
+                TcpOption::WindowScale(7),
+            ]
+        );
+    }
 
-    let mut output = vec![0; 44 + text.len()];
-    set_u16_be(&mut output[2..4], 0x0800);
-    output[4..24].copy_from_slice(&ip.serialize()[0..20]);
-    output[24..44].copy_from_slice(&tcp.serialize()[0..20]);
-    output[44..44 + text.len()].copy_from_slice(text);
-    output
+    #[test]
+    fn frames_are_built_in_place() {
+        let id = ConnectionId {
+            ip_src: 0x0a000002,
+            ip_dst: 0x0a000003,
+            port_src: 49152,
+            port_dst: 8080,
+        };
+        let expected = build_tcp_packet(&id, TcpFlag::Ack as u8, 1, 2, b"hello");
+
+        // a payload split in pieces of odd length
+        let mut frame = Vec::with_capacity(100);
+        frame.extend([0, 0, 0x08, 0x00]);
+        let text: [&[u8]; 2] = [b"hel", b"lo"];
+        write_tcp_packet(
+            &mut frame,
+            &id,
+            TcpFlag::Ack as u8,
+            1,
+            2,
+            WINDOW,
+            &[],
+            &text,
+            0,
+            false,
+        );
+        assert_eq!(frame, expected);
+
+        // completing a partial checksum gives the full one
+        let mut partial = frame[..4].to_vec();
+        write_tcp_packet(
+            &mut partial,
+            &id,
+            TcpFlag::Ack as u8,
+            1,
+            2,
+            WINDOW,
+            &[],
+            &text,
+            0,
+            true,
+        );
+        assert_eq!(
+            checksum::checksum(&partial[24..]),
+            u16::from_be_bytes([frame[40], frame[41]])
+        );
+
+        let (ip, data) = ipv4::IPv4Header::new(&frame[4..]).unwrap();
+        assert_eq!(ip.header_checksum, ip.calc_checksum());
+        let (tcp, text) = TcpHeader::new(data).unwrap();
+        assert_eq!(text, b"hello");
+        assert_eq!(
+            tcp.checksum,
+            tcp.calc_checksum(ip.source_ip, ip.dest_ip, data.len(), text)
+        );
+    }
+
+    #[test]
+    fn malformed_options() {
+        let options = |data| TcpOptions { data }.collect::<Vec<_>>();
+
+        assert!(options(&[0, 2, 4, 5, 0xb4]).is_empty());
+        assert!(matches!(options(&[2, 4, 5])[..], [Err(_)]));
+        assert!(matches!(options(&[2, 3, 5])[..], [Err(_)]));
+        assert!(matches!(options(&[30, 0])[..], [Err(_)]));
+        assert!(matches!(
+            options(&[30, 3, 1, 1])[..],
+            [Ok(TcpOption::Unknown(30, &[1])), Ok(TcpOption::Nop)]
+        ));
+    }
 }
@@ -0,0 +1,301 @@
+// a hierarchical timer wheel, like the ones in linux and tokio. Level n has
+// SLOTS slots of SLOTS^n ticks each, a timer goes into the lowest level whose
+// current rotation contains its deadline and moves down a level whenever its
+// slot comes up. The last level wraps around, its slots before the current
+// one belong to the next rotation. Scheduling and cancelling are O(1), a
+// cancelled timer only leaves a stale entry behind that is dropped when its
+// slot is processed
+
+use std::{
+    mem,
+    time::{Duration, Instant},
+};
+
+const SLOTS: usize = 64;
+const LEVELS: usize = 4;
+const TICK: Duration = Duration::from_millis(1);
+// how far ahead of the current tick the last level reaches without coming
+// back to the current slot, 63 * 64^3 ms is about 4.6 hours. A later timer
+// waits in the last slot and is put back into the wheel when that comes up
+const MAX_TICKS: u64 = (SLOTS as u64 - 1) * (SLOTS as u64).pow(LEVELS as u32 - 1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TimerId {
+    index: usize,
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct Entry<K> {
+    // None once the timer has fired or was cancelled
+    key: Option<K>,
+    generation: u64,
+    at: u64,
+}
+
+#[derive(Debug)]
+struct Level {
+    // bit n is set when slot n has entries, some of them may be stale
+    occupied: u64,
+    slots: Vec<Vec<TimerId>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct TimerWheel<K> {
+    start: Instant,
+    // ticks since start that have been processed
+    elapsed: u64,
+    levels: Vec<Level>,
+    entries: Vec<Entry<K>>,
+    free: Vec<usize>,
+}
+
+impl<K> TimerWheel<K> {
+    pub(crate) fn new(start: Instant) -> TimerWheel<K> {
+        TimerWheel {
+            start,
+            elapsed: 0,
+            levels: (0..LEVELS)
+                .map(|_| Level {
+                    occupied: 0,
+                    slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+                })
+                .collect(),
+            entries: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    // `key` is returned by expire() once `at` has passed
+    pub(crate) fn schedule(&mut self, key: K, at: Instant) -> TimerId {
+        // rounded up, a timer never fires early
+        let ticks = at
+            .saturating_duration_since(self.start)
+            .as_nanos()
+            .div_ceil(TICK.as_nanos())
+            .min(u64::MAX.into()) as u64;
+
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.entries.push(Entry {
+                    key: None,
+                    generation: 0,
+                    at: 0,
+                });
+                self.entries.len() - 1
+            }
+        };
+
+        let entry = &mut self.entries[index];
+        entry.key = Some(key);
+        entry.generation += 1;
+        entry.at = ticks.max(self.elapsed);
+
+        let id = TimerId {
+            index,
+            generation: entry.generation,
+        };
+        self.insert(id);
+        id
+    }
+
+    // returns whether the timer was still pending
+    pub(crate) fn cancel(&mut self, id: TimerId) -> bool {
+        match self.entries.get_mut(id.index) {
+            Some(entry) if entry.generation == id.generation && entry.key.is_some() => {
+                entry.key = None;
+                self.free.push(id.index);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // every timer whose deadline is not after `now`, in no particular order
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<(TimerId, K)> {
+        let now = now.saturating_duration_since(self.start).as_nanos() / TICK.as_nanos();
+        let now = now.min(u64::MAX.into()) as u64;
+        let mut expired = Vec::new();
+
+        while let Some((level, slot, at)) = self.next_slot() {
+            if at > now {
+                break;
+            }
+
+            self.elapsed = self.elapsed.max(at);
+            self.levels[level].occupied &= !(1 << slot);
+            for id in mem::take(&mut self.levels[level].slots[slot]) {
+                let entry = &mut self.entries[id.index];
+                if entry.generation != id.generation || entry.key.is_none() {
+                    continue;
+                }
+
+                if entry.at <= self.elapsed {
+                    expired.push((id, entry.key.take().unwrap()));
+                    self.free.push(id.index);
+                } else {
+                    self.insert(id);
+                }
+            }
+        }
+
+        self.elapsed = self.elapsed.max(now);
+        expired
+    }
+
+    // when expire() has something to do next, possibly a bit early because
+    // of cancelled timers or timers that only move down a level
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        let (_, _, at) = self.next_slot()?;
+        Some(self.start + Duration::from_nanos(at * TICK.as_nanos() as u64))
+    }
+
+    fn insert(&mut self, id: TimerId) {
+        let at = self.entries[id.index].at.min(self.elapsed + MAX_TICKS);
+        // the highest level on which the deadline and now are in different slots
+        let masked = (self.elapsed ^ at) | (SLOTS as u64 - 1);
+        let level = ((63 - masked.leading_zeros()) / SLOTS.ilog2()) as usize;
+        let level = level.min(LEVELS - 1);
+
+        let slot = (at >> (level as u32 * SLOTS.ilog2())) as usize % SLOTS;
+        self.levels[level].occupied |= 1 << slot;
+        self.levels[level].slots[slot].push(id);
+    }
+
+    // the first occupied slot and the tick it starts at, lower levels always
+    // come first because they only hold deadlines within the current slot of
+    // the level above
+    fn next_slot(&self) -> Option<(usize, usize, u64)> {
+        for (n, level) in self.levels.iter().enumerate() {
+            if level.occupied == 0 {
+                continue;
+            }
+
+            let slot_ticks = (SLOTS as u64).pow(n as u32);
+            let current = self.elapsed / slot_ticks;
+
+            // slots after the current one, wrapping around on the last level
+            let ahead = level
+                .occupied
+                .rotate_right((current % SLOTS as u64) as u32)
+                .trailing_zeros() as u64;
+            let slot = ((current + ahead) % SLOTS as u64) as usize;
+            let at = ((current + ahead) * slot_ticks).max(self.elapsed);
+
+            return Some((n, slot, at));
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn keys(mut expired: Vec<(TimerId, u32)>) -> Vec<u32> {
+        expired.sort_by_key(|(_, key)| *key);
+        expired.into_iter().map(|(_, key)| key).collect()
+    }
+
+    #[test]
+    fn fires_at_the_deadline_and_not_before() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(start);
+        for (key, at) in [(1, 5), (2, 63), (3, 64), (4, 5000), (5, 60_000)] {
+            wheel.schedule(key, start + ms(at));
+        }
+
+        assert_eq!(keys(wheel.expire(start + ms(4))), []);
+        assert_eq!(keys(wheel.expire(start + ms(5))), [1]);
+        assert_eq!(keys(wheel.expire(start + ms(64))), [2, 3]);
+        assert_eq!(keys(wheel.expire(start + ms(4999))), []);
+        assert_eq!(keys(wheel.expire(start + ms(5000))), [4]);
+        assert_eq!(keys(wheel.expire(start + ms(59_999))), []);
+        assert_eq!(keys(wheel.expire(start + ms(60_000))), [5]);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn cancelled_timers_do_not_fire() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(start);
+        let a = wheel.schedule(1, start + ms(10));
+        wheel.schedule(2, start + ms(10));
+
+        assert!(wheel.cancel(a));
+        assert!(!wheel.cancel(a));
+
+        // the entry of a cancelled timer is reused without reviving it
+        let b = wheel.schedule(3, start + ms(20));
+        assert_eq!(b.index, a.index);
+        assert!(!wheel.cancel(a));
+
+        assert_eq!(keys(wheel.expire(start + ms(10))), [2]);
+        assert_eq!(keys(wheel.expire(start + ms(20))), [3]);
+    }
+
+    #[test]
+    fn next_deadline_is_never_late() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(start);
+        wheel.schedule(1, start + ms(3000));
+        wheel.expire(start + ms(100));
+
+        // stepping through next_deadline reaches the timer without skipping it
+        let mut steps = 0;
+        while let Some(at) = wheel.next_deadline() {
+            assert!(at <= start + ms(3000));
+            if !wheel.expire(at).is_empty() {
+                assert_eq!(at, start + ms(3000));
+                break;
+            }
+            steps += 1;
+            assert!(steps < LEVELS);
+        }
+
+        // deadlines in the past fire on the next expire
+        wheel.schedule(2, start);
+        assert_eq!(wheel.next_deadline(), Some(start + ms(3000)));
+        assert_eq!(keys(wheel.expire(start + ms(3000))), [2]);
+    }
+
+    // steps through next_deadline like the processing loop does, returns
+    // when the timer fired and how many wakeups it took
+    fn run_until_fired(wheel: &mut TimerWheel<u32>) -> (Instant, usize) {
+        let mut steps = 0;
+        loop {
+            let at = wheel.next_deadline().unwrap();
+            steps += 1;
+            if !wheel.expire(at).is_empty() {
+                return (at, steps);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_going_past_the_reach_of_the_last_level() {
+        let start = Instant::now();
+        let mut wheel = TimerWheel::new(start);
+        let hour = ms(3600 * 1000);
+        wheel.expire(start + 5 * hour);
+
+        // a short timer does not fire before its deadline
+        wheel.schedule(1, start + 5 * hour + ms(60_000));
+        assert!(wheel.next_deadline().unwrap() > start + 5 * hour);
+        assert_eq!(keys(wheel.expire(start + 5 * hour + ms(59_999))), []);
+        assert_eq!(run_until_fired(&mut wheel).0, start + 5 * hour + ms(60_000));
+
+        // and one further ahead than the wheel reaches fires neither early
+        // nor after going around in circles
+        wheel.schedule(2, start + 15 * hour);
+        let (at, steps) = run_until_fired(&mut wheel);
+        assert_eq!(at, start + 15 * hour);
+        assert!(steps < 3 * LEVELS);
+    }
+}
@@ -0,0 +1,42 @@
+use std::{
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+};
+
+// interrupts the processing thread's poll when an application thread has
+// given it something to send. The bytes stay in the socket until the
+// processing thread clears them, so a wakeup that happens before the thread
+// starts polling is not lost
+#[derive(Debug)]
+pub(crate) struct Wakeup {
+    rx: UnixStream,
+    tx: UnixStream,
+}
+
+impl Wakeup {
+    pub(crate) fn new() -> io::Result<Wakeup> {
+        let (rx, tx) = UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        tx.set_nonblocking(true)?;
+        Ok(Wakeup { rx, tx })
+    }
+
+    // a full socket already has a wakeup pending, so errors are ignored
+    pub(crate) fn wake(&self) {
+        let _ = (&self.tx).write(&[1]);
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut buf = [0; 64];
+        while matches!((&self.rx).read(&mut buf), Ok(n) if n > 0) {}
+    }
+}
+
+impl AsRawFd for Wakeup {
+    fn as_raw_fd(&self) -> RawFd {
+        self.rx.as_raw_fd()
+    }
+}
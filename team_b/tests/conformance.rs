@@ -0,0 +1,538 @@
+// runs the packetdrill-like scripts in tests/scripts against a stepped stack.
+// The test plays the remote end of a MemoryDevice pair and every line of a
+// script is one of
+//
+//   <time> < <flags> <start>:<end>(<len>) [ack <n>] [win <n>] [<options>] ["payload"]
+//       inject a segment from the remote end
+//   <time> > <flags> <start>:<end>(<len>) [ack <n>] [win <n>] [<options>] ["payload"]
+//       the next segment the stack sends has to match, ack, win, options and
+//       payload are only compared when given
+//   <time> listen <port> | connect <port> | accept | write "text" | read "text"
+//          | read eof | read error <io::ErrorKind> | shutdown read|write|both
+//          | close | abort
+//       a call on the socket api, the connection is nonblocking
+//
+// Flags are packetdrill's S F R P . (ACK). Sequence numbers of the remote end
+// are used as written, the ones of the stack are relative to its ISN, which is
+// taken from the first SYN or SYN-ACK it sends. Options are a comma separated
+// list of `mss <n>`, `wscale <n>`, `sackOK`, `TS val <n> ecr <n>` and `nop`.
+// The clock is virtual and only moves forward to the time of each line, the
+// stack's timers only run when a `>` line has to wait for a segment
+
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    os::fd::AsRawFd,
+    path::Path,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use nix::poll::{poll, PollFd, PollFlags};
+use tcp::{
+    clock::VirtualClock,
+    device::{MemoryDevice, NetDevice},
+    ipv4::IPv4Header,
+    net::{TcpListener, TcpStream},
+    tcp::{TcpFlag, TcpHeader, TcpOption},
+    ConnectionManager, Stepper,
+};
+
+const STACK_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+const REMOTE_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+// where injected segments come from, unless connect picked another port
+const REMOTE_PORT: u16 = 40000;
+
+#[derive(Debug, Default)]
+struct Segment {
+    flags: u8,
+    seq: u32,
+    len: u32,
+    ack: Option<u32>,
+    win: Option<u16>,
+    options: Option<Vec<u8>>,
+    payload: Option<Vec<u8>>,
+}
+
+enum Line {
+    Inject(Segment),
+    Expect(Segment),
+    Call(Vec<String>),
+}
+
+fn parse_flags(s: &str) -> Result<u8, String> {
+    s.chars().try_fold(0, |flags, c| {
+        let flag = match c {
+            'S' => TcpFlag::Syn,
+            'F' => TcpFlag::Fin,
+            'R' => TcpFlag::Rst,
+            'P' => TcpFlag::Psh,
+            'U' => TcpFlag::Urg,
+            'E' => TcpFlag::Ece,
+            'W' => TcpFlag::Cwr,
+            '.' => TcpFlag::Ack,
+            _ => return Err(format!("unknown flag {c:?}")),
+        };
+        Ok(flags | flag as u8)
+    })
+}
+
+fn parse_num<T: std::str::FromStr>(s: Option<&str>) -> Result<T, String> {
+    let s = s.ok_or("missing number")?;
+    s.parse().map_err(|_| format!("invalid number {s:?}"))
+}
+
+// <mss 1460,nop,wscale 7>, already split on whitespace
+fn parse_options(words: &[String]) -> Result<Vec<u8>, String> {
+    let list = words.join(" ");
+    let list = list.trim_start_matches('<').trim_end_matches('>');
+    let mut options = Vec::new();
+
+    for option in list.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+        let mut words = option.split_whitespace();
+        let option = match words.next() {
+            Some("nop") => TcpOption::Nop,
+            Some("sackOK") => TcpOption::SackPermitted,
+            Some("mss") => TcpOption::Mss(parse_num(words.next())?),
+            Some("wscale") => TcpOption::WindowScale(parse_num(words.next())?),
+            Some("TS") => {
+                let (_, val, _, ecr) = (words.next(), words.next(), words.next(), words.next());
+                TcpOption::Timestamps(parse_num(val)?, parse_num(ecr)?)
+            }
+            _ => return Err(format!("unknown option {option:?}")),
+        };
+        options.extend(option.serialize());
+    }
+
+    // the header length is counted in 32 bit words, pad with end of options
+    options.resize(options.len().div_ceil(4) * 4, 0);
+    Ok(options)
+}
+
+// S 0:0(0) ack 1 win 1500 <mss 1460> "payload"
+fn parse_segment(words: &[String]) -> Result<Segment, String> {
+    let mut segment = Segment {
+        flags: parse_flags(words.first().ok_or("missing flags")?)?,
+        ..Segment::default()
+    };
+
+    let range = words.get(1).ok_or("missing sequence numbers")?;
+    let (start, rest) = range
+        .split_once(':')
+        .ok_or("expected <start>:<end>(<len>)")?;
+    let (end, len) = rest
+        .trim_end_matches(')')
+        .split_once('(')
+        .ok_or("expected (<len>)")?;
+    segment.seq = parse_num(Some(start))?;
+    segment.len = parse_num(Some(len))?;
+    if parse_num::<u32>(Some(end))?.wrapping_sub(segment.seq) != segment.len {
+        return Err(format!("{range} has the wrong length"));
+    }
+
+    let mut i = 2;
+    while let Some(word) = words.get(i) {
+        match word.as_str() {
+            "ack" => segment.ack = Some(parse_num(words.get(i + 1).map(|s| s.as_str()))?),
+            "win" => segment.win = Some(parse_num(words.get(i + 1).map(|s| s.as_str()))?),
+            w if w.starts_with('<') => {
+                let end = (i..words.len())
+                    .find(|&j| words[j].ends_with('>'))
+                    .ok_or("unterminated options")?;
+                segment.options = Some(parse_options(&words[i..=end])?);
+                i = end + 1;
+                continue;
+            }
+            w if w.starts_with('"') => {
+                let payload = w.trim_matches('"').as_bytes().to_vec();
+                if payload.len() as u32 != segment.len {
+                    return Err(format!("payload {w} does not have length {}", segment.len));
+                }
+                segment.payload = Some(payload);
+                i += 1;
+                continue;
+            }
+            w => return Err(format!("unexpected {w:?}")),
+        }
+        i += 2;
+    }
+
+    Ok(segment)
+}
+
+// whitespace separated words, "quoted strings" are kept together
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut rest = line.trim();
+
+    while !rest.is_empty() {
+        let end = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.find('"').map_or(rest.len(), |i| i + 2),
+            None => rest.find(char::is_whitespace).unwrap_or(rest.len()),
+        };
+        words.push(rest[..end].to_string());
+        rest = rest[end..].trim_start();
+    }
+
+    words
+}
+
+fn parse_line(line: &str) -> Result<(Duration, Line), String> {
+    let words = split_words(line);
+    let time: f64 = parse_num(words.first().map(|s| s.as_str()))?;
+    let time = Duration::from_secs_f64(time);
+
+    let line = match words.get(1).map(|s| s.as_str()) {
+        Some("<") => Line::Inject(parse_segment(&words[2..])?),
+        Some(">") => Line::Expect(parse_segment(&words[2..])?),
+        Some(_) => Line::Call(words[1..].to_vec()),
+        None => return Err("missing action".to_string()),
+    };
+
+    Ok((time, line))
+}
+
+struct Runner {
+    clock: Arc<VirtualClock>,
+    mgr: ConnectionManager,
+    stepper: Stepper<MemoryDevice>,
+    remote: MemoryDevice,
+    now: Duration,
+    listener: Option<TcpListener>,
+    connecting: Option<JoinHandle<std::io::Result<TcpStream>>>,
+    stream: Option<TcpStream>,
+    remote_port: u16,
+    stack_port: u16,
+    stack_isn: Option<u32>,
+}
+
+impl Runner {
+    fn new() -> Runner {
+        let clock = Arc::new(VirtualClock::new());
+        let (stack, remote) = MemoryDevice::pair().unwrap();
+        let (mgr, stepper) = ConnectionManager::stepped(stack, clock.clone()).unwrap();
+
+        Runner {
+            clock,
+            mgr,
+            stepper,
+            remote,
+            now: Duration::ZERO,
+            listener: None,
+            connecting: None,
+            stream: None,
+            remote_port: REMOTE_PORT,
+            stack_port: 0,
+            stack_isn: None,
+        }
+    }
+
+    fn run(&mut self, script: &str) -> Result<(), String> {
+        for (number, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let result = parse_line(line).and_then(|(time, line)| {
+                if time > self.now {
+                    self.clock.advance(time - self.now);
+                    self.now = time;
+                }
+
+                match line {
+                    Line::Inject(segment) => self.inject(&segment),
+                    Line::Expect(segment) => self.expect(&segment),
+                    Line::Call(words) => self.call(&words),
+                }
+            });
+
+            result.map_err(|e| format!("line {}: {line}\n  {e}", number + 1))?;
+        }
+
+        self.expect_nothing()
+            .map_err(|e| format!("end of script\n  {e}"))
+    }
+
+    fn process(&mut self) -> Result<(), String> {
+        while self.stepper.step().map_err(|e| e.to_string())? {}
+        Ok(())
+    }
+
+    // waits up to timeout_ms for the stack to send something
+    fn wait_for_segment(&self, timeout_ms: i32) -> bool {
+        let fd = PollFd::new(self.remote.as_raw_fd(), PollFlags::POLLIN);
+        poll(&mut [fd], timeout_ms) == Ok(1)
+    }
+
+    fn sent(&mut self) -> Option<Vec<u8>> {
+        if !self.wait_for_segment(0) {
+            return None;
+        }
+
+        let mut buf = vec![0; 65536];
+        let len = self.remote.recv(&mut buf).ok()?;
+        buf.truncate(len);
+        Some(buf)
+    }
+
+    fn expect_nothing(&mut self) -> Result<(), String> {
+        self.process()?;
+        match self.sent() {
+            Some(frame) => Err(format!("unexpected segment {}", self.describe(&frame))),
+            None => Ok(()),
+        }
+    }
+
+    fn inject(&mut self, segment: &Segment) -> Result<(), String> {
+        self.expect_nothing()?;
+
+        let payload = segment
+            .payload
+            .clone()
+            .unwrap_or_else(|| vec![0; segment.len as usize]);
+        let options = segment.options.clone().unwrap_or_default();
+        let isn = self.stack_isn.unwrap_or(0);
+
+        let mut tcp = TcpHeader {
+            source_port: self.remote_port,
+            dest_port: self.stack_port,
+            sequence_number: segment.seq,
+            ack_number: segment.ack.map_or(0, |ack| ack.wrapping_add(isn)),
+            data_offset: 5 + options.len() as u8 / 4,
+            flags: segment.flags,
+            window_size: segment.win.unwrap_or(1500),
+            checksum: 0,
+            urgent_pointer: 0,
+            options: &options,
+        };
+        let mut ip = IPv4Header {
+            version: 4,
+            ihl: 5,
+            dscp: 0,
+            ecn: 0,
+            total_length: (20 + tcp.size() + payload.len()) as u16,
+            identification: 0,
+            flags: 0b010,
+            fragment_offset: 0,
+            time_to_live: 64,
+            protocol: 6,
+            header_checksum: 0,
+            source_ip: REMOTE_IP.into(),
+            dest_ip: STACK_IP.into(),
+            options: &[],
+        };
+        ip.header_checksum = ip.calc_checksum();
+        tcp.checksum = tcp.calc_checksum(
+            ip.source_ip,
+            ip.dest_ip,
+            tcp.size() + payload.len(),
+            &payload,
+        );
+
+        let mut frame = vec![0, 0, 0x08, 0x00];
+        frame.extend(&ip.serialize()[..ip.size()]);
+        frame.extend(&tcp.serialize()[..tcp.size()]);
+        frame.extend(&payload);
+
+        self.remote.send(&frame).map_err(|e| e.to_string())?;
+        self.process()
+    }
+
+    fn expect(&mut self, expected: &Segment) -> Result<(), String> {
+        self.process()?;
+
+        let frame = match self.sent() {
+            Some(frame) => frame,
+            None => {
+                self.stepper.tick().map_err(|e| e.to_string())?;
+                self.sent().ok_or("the stack did not send anything")?
+            }
+        };
+
+        let (ip, data) = IPv4Header::new(&frame[4..]).map_err(|e| e.to_string())?;
+        let (tcp, text) = TcpHeader::new(data).map_err(|e| e.to_string())?;
+        if ip.header_checksum != ip.calc_checksum()
+            || tcp.checksum
+                != tcp.calc_checksum(ip.source_ip, ip.dest_ip, tcp.size() + text.len(), text)
+        {
+            return Err("the stack sent a segment with a bad checksum".to_string());
+        }
+
+        if tcp.get_flag(TcpFlag::Syn) && self.stack_isn.is_none() {
+            self.stack_isn = Some(tcp.sequence_number);
+            self.stack_port = tcp.source_port;
+        }
+        let seq = tcp
+            .sequence_number
+            .wrapping_sub(self.stack_isn.unwrap_or(0));
+
+        let matches = tcp.flags == expected.flags
+            && seq == expected.seq
+            && text.len() as u32 == expected.len
+            && expected.ack.is_none_or(|ack| ack == tcp.ack_number)
+            && expected.win.is_none_or(|win| win == tcp.window_size)
+            && expected.options.as_ref().is_none_or(|o| o == tcp.options)
+            && expected.payload.as_ref().is_none_or(|p| p == text);
+
+        match matches {
+            true => Ok(()),
+            false => Err(format!("got {}", self.describe(&frame))),
+        }
+    }
+
+    // the segment in script syntax
+    fn describe(&self, frame: &[u8]) -> String {
+        let Ok((_, data)) = IPv4Header::new(&frame[4..]) else {
+            return format!("{frame:02x?}");
+        };
+        let Ok((tcp, text)) = TcpHeader::new(data) else {
+            return format!("{frame:02x?}");
+        };
+
+        let flags: String = [
+            (TcpFlag::Syn, 'S'),
+            (TcpFlag::Fin, 'F'),
+            (TcpFlag::Rst, 'R'),
+            (TcpFlag::Psh, 'P'),
+            (TcpFlag::Ack, '.'),
+        ]
+        .into_iter()
+        .filter_map(|(flag, c)| tcp.get_flag(flag).then_some(c))
+        .collect();
+        let seq = tcp
+            .sequence_number
+            .wrapping_sub(self.stack_isn.unwrap_or(0));
+
+        format!(
+            "{flags} {seq}:{}({}) ack {} win {}",
+            seq.wrapping_add(text.len() as u32),
+            text.len(),
+            tcp.ack_number,
+            tcp.window_size
+        )
+    }
+
+    fn stream(&mut self) -> Result<&TcpStream, String> {
+        if let Some(connecting) = self.connecting.take() {
+            let stream = connecting.join().unwrap().map_err(|e| e.to_string())?;
+            stream.set_nonblocking(true).unwrap();
+            self.stream = Some(stream);
+        }
+
+        self.stream.as_ref().ok_or("no connection".to_string())
+    }
+
+    fn call(&mut self, words: &[String]) -> Result<(), String> {
+        self.expect_nothing()?;
+
+        let arg = |i: usize| words.get(i).map(|s| s.as_str()).unwrap_or_default();
+        let text = |i: usize| arg(i).trim_matches('"').as_bytes().to_vec();
+
+        match arg(0) {
+            "listen" => {
+                let port = parse_num(Some(arg(1)))?;
+                let addr = SocketAddrV4::new(STACK_IP, port);
+                let listener =
+                    TcpListener::bind_with(&self.mgr, addr).map_err(|e| e.to_string())?;
+                listener.set_nonblocking(true).unwrap();
+                self.listener = Some(listener);
+                self.stack_port = port;
+            }
+            "accept" => {
+                let listener = self.listener.as_ref().ok_or("not listening")?;
+                let (stream, _) = listener.accept().map_err(|e| e.to_string())?;
+                stream.set_nonblocking(true).unwrap();
+                self.stream = Some(stream);
+            }
+            "connect" => {
+                self.remote_port = parse_num(Some(arg(1)))?;
+                let addr = SocketAddrV4::new(REMOTE_IP, self.remote_port);
+                let mgr = self.mgr.clone();
+                let timeout = Some(Duration::from_secs(10));
+                let connecting =
+                    thread::spawn(move || TcpStream::connect_with(&mgr, STACK_IP, addr, timeout));
+
+                // connect() runs on its own thread so that the script can
+                // continue, the SYN goes out on the first tick after it has
+                // registered the connection
+                while !connecting.is_finished() && !self.wait_for_segment(10) {
+                    self.stepper.tick().map_err(|e| e.to_string())?;
+                }
+                self.connecting = Some(connecting);
+            }
+            "write" => {
+                let data = text(1);
+                let written = self.stream()?.write(&data).map_err(|e| e.to_string())?;
+                if written != data.len() {
+                    return Err(format!("wrote only {written} bytes"));
+                }
+            }
+            "read" => {
+                let mut buf = vec![0; 65536];
+                let result = self.stream()?.read(&mut buf);
+                let got = match &result {
+                    Ok(0) => "eof".to_string(),
+                    Ok(len) => format!("\"{}\"", String::from_utf8_lossy(&buf[..*len])),
+                    Err(e) => format!("error {:?}", e.kind()),
+                };
+                if got != words[1..].join(" ") {
+                    return Err(format!("read returned {got}"));
+                }
+            }
+            "shutdown" => {
+                let how = match arg(1) {
+                    "read" => Shutdown::Read,
+                    "write" => Shutdown::Write,
+                    "both" => Shutdown::Both,
+                    how => return Err(format!("unknown shutdown {how:?}")),
+                };
+                self.stream()?.shutdown(how).map_err(|e| e.to_string())?;
+            }
+            "close" => {
+                self.stream()?;
+                self.stream.take().unwrap().into_inner().close();
+            }
+            "abort" => {
+                self.stream()?;
+                self.stream.take().unwrap().into_inner().abort();
+            }
+            call => return Err(format!("unknown call {call:?}")),
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn scripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "pkt"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no scripts in {}", dir.display());
+
+    let mut failures = Vec::new();
+    for path in paths {
+        let script = fs::read_to_string(&path).unwrap();
+        if let Err(e) = Runner::new().run(&script) {
+            failures.push(format!(
+                "{}: {e}",
+                path.file_name().unwrap().to_string_lossy()
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[test]
+fn unknown_calls_fail() {
+    let err = Runner::new().run("0 frobnicate").unwrap_err();
+    assert!(err.contains("unknown call"), "{err}");
+}
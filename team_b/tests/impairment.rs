@@ -0,0 +1,98 @@
+// the loopback tests again, over a link that loses, duplicates, reorders and
+// corrupts frames in both directions
+
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    thread,
+    time::Duration,
+};
+
+use tcp::{
+    device::MemoryDevice,
+    impairment::{ImpairedDevice, Impairment, ImpairmentConfig},
+    net::{TcpListener, TcpStream},
+    ConnectionManager,
+};
+
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn bad_link(seed: u64) -> ImpairmentConfig {
+    let impairment = Impairment {
+        loss: 0.1,
+        duplicate: 0.1,
+        corrupt: 0.05,
+        reorder: 0.1,
+        reorder_delay: Duration::from_millis(30),
+        delay: Duration::from_millis(5),
+        jitter: Duration::from_millis(5),
+        rate: Some(1_000_000),
+    };
+
+    ImpairmentConfig {
+        egress: impairment.clone(),
+        ingress: impairment,
+        seed,
+    }
+}
+
+#[test]
+fn transfer_over_a_bad_link() {
+    let (server, client) = MemoryDevice::pair().unwrap();
+    let server = ConnectionManager::new(ImpairedDevice::new(server, bad_link(1)).unwrap()).unwrap();
+    let client = ConnectionManager::new(ImpairedDevice::new(client, bad_link(2)).unwrap()).unwrap();
+
+    let listener = TcpListener::bind_with(&server, SocketAddrV4::new(SERVER_IP, 8080)).unwrap();
+    let data: Vec<u8> = (0..8 * 1024).map(|i| (i % 251) as u8).collect();
+
+    let expected = data.clone();
+    let writer = thread::spawn(move || {
+        let addr = SocketAddrV4::new(SERVER_IP, 8080);
+        let timeout = Some(Duration::from_secs(30));
+        let mut stream = TcpStream::connect_with(&client, CLIENT_IP, addr, timeout).unwrap();
+
+        stream.write_all(&data).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+
+    let mut received = Vec::new();
+    (&stream).read_to_end(&mut received).unwrap();
+
+    writer.join().unwrap();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn rejects_invalid_probabilities() {
+    let (device, _peer) = MemoryDevice::pair().unwrap();
+    let config = ImpairmentConfig {
+        egress: Impairment {
+            loss: 1.5,
+            ..Impairment::default()
+        },
+        ..ImpairmentConfig::default()
+    };
+
+    assert!(ImpairedDevice::new(device, config).is_err());
+}
+
+#[test]
+fn rejects_a_zero_rate() {
+    let (device, _peer) = MemoryDevice::pair().unwrap();
+    let config = ImpairmentConfig {
+        ingress: Impairment {
+            rate: Some(0),
+            ..Impairment::default()
+        },
+        ..ImpairmentConfig::default()
+    };
+
+    let err = ImpairedDevice::new(device, config).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
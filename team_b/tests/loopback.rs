@@ -0,0 +1,195 @@
+// two stacks in one process, connected by a MemoryDevice pair instead of tun0
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddrV4},
+    thread,
+    time::{Duration, Instant},
+};
+
+use tcp::{
+    device::{MemoryDevice, Offloads},
+    net::{TcpListener, TcpStream},
+    ConnectionManager,
+};
+
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+fn network() -> (ConnectionManager, ConnectionManager) {
+    managers(MemoryDevice::pair().unwrap())
+}
+
+fn managers(
+    (server, client): (MemoryDevice, MemoryDevice),
+) -> (ConnectionManager, ConnectionManager) {
+    (
+        ConnectionManager::new(server).unwrap(),
+        ConnectionManager::new(client).unwrap(),
+    )
+}
+
+// a connected (server, client) pair of streams
+fn connect(port: u16) -> (TcpStream, TcpStream) {
+    connect_over(network(), port)
+}
+
+fn connect_over(
+    (server, client): (ConnectionManager, ConnectionManager),
+    port: u16,
+) -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind_with(&server, SocketAddrV4::new(SERVER_IP, port)).unwrap();
+
+    let client = thread::spawn(move || {
+        let addr = SocketAddrV4::new(SERVER_IP, port);
+        TcpStream::connect_with(&client, CLIENT_IP, addr, Some(Duration::from_secs(10))).unwrap()
+    });
+    let (server, peer) = listener.accept().unwrap();
+    let client = client.join().unwrap();
+
+    assert_eq!(peer, client.local_addr().unwrap());
+    assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+    (server, client)
+}
+
+fn read_exact(mut stream: &TcpStream, len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn handshake_and_data_both_ways() {
+    let (mut server, mut client) = connect(8080);
+
+    client.write_all(b"hello server").unwrap();
+    assert_eq!(read_exact(&server, 12), b"hello server");
+
+    server.write_all(b"hello client").unwrap();
+    assert_eq!(read_exact(&client, 12), b"hello client");
+}
+
+#[test]
+fn transfer_larger_than_a_segment() {
+    let (server, mut client) = connect(8081);
+    let data: Vec<u8> = (0..8 * 1024).map(|i| (i % 251) as u8).collect();
+
+    let writer = thread::spawn(move || {
+        client.write_all(&data).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        data
+    });
+
+    let mut received = Vec::new();
+    (&server).read_to_end(&mut received).unwrap();
+
+    assert_eq!(received, writer.join().unwrap());
+}
+
+// frames carry a vnet header and only a partial checksum, which the
+// receiving side has to accept without verifying it
+#[test]
+fn transfer_over_offloading_devices() {
+    let offloads = Offloads {
+        vnet_hdr: true,
+        checksum: true,
+        tso: true,
+    };
+    let network = managers(MemoryDevice::pair_with_offloads(offloads).unwrap());
+    let (server, mut client) = connect_over(network, 8087);
+    let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+
+    let writer = thread::spawn(move || {
+        client.write_all(&data).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        data
+    });
+
+    let mut received = Vec::new();
+    (&server).read_to_end(&mut received).unwrap();
+
+    assert_eq!(received, writer.join().unwrap());
+}
+
+// segments of a link with jumbo frames are larger than the default MTU,
+// the receiving side must not cut them down to that
+#[test]
+fn transfer_over_a_link_with_jumbo_frames() {
+    let network = managers(MemoryDevice::pair_with_mtu(9000).unwrap());
+    assert_eq!(network.0.mtu(), 9000);
+    let (server, mut client) = connect_over(network, 8088);
+    let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+
+    let writer = thread::spawn(move || {
+        client.write_all(&data).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        data
+    });
+
+    let mut received = Vec::new();
+    (&server).read_to_end(&mut received).unwrap();
+
+    assert_eq!(received, writer.join().unwrap());
+}
+
+// each exchange only waits for the other side, not for a timer
+#[test]
+fn round_trips_are_not_paced_by_a_timer() {
+    let (mut server, mut client) = connect(8085);
+    let start = Instant::now();
+
+    for i in 0..100u8 {
+        client.write_all(&[i]).unwrap();
+        assert_eq!(read_exact(&server, 1), [i]);
+        server.write_all(&[i]).unwrap();
+        assert_eq!(read_exact(&client, 1), [i]);
+    }
+
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn close_is_seen_as_eof() {
+    let (server, mut client) = connect(8082);
+    client.set_linger(Some(Duration::from_secs(10))).unwrap();
+
+    client.write_all(b"bye").unwrap();
+    assert!(client.into_inner().close());
+
+    let mut buf = Vec::new();
+    (&server).read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"bye");
+}
+
+#[test]
+fn abort_resets_the_peer() {
+    let (server, client) = connect(8083);
+    server
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+
+    client.into_inner().abort();
+
+    let err = (&server).read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+}
+
+#[test]
+fn nonblocking_read_would_block() {
+    let (server, _client) = connect(8084);
+    server.set_nonblocking(true).unwrap();
+
+    let err = (&server).read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+}
+
+#[test]
+fn connect_without_listener_times_out() {
+    let (_server, client) = network();
+
+    let addr = SocketAddrV4::new(SERVER_IP, 9999);
+    let result =
+        TcpStream::connect_with(&client, CLIENT_IP, addr, Some(Duration::from_millis(500)));
+
+    assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::TimedOut));
+}